@@ -0,0 +1,51 @@
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    pub fn from_extension(ext: &str) -> Option<Compression> {
+        match ext {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" | "bzip2" => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    // Content-Encoding header values
+    pub fn from_encoding(encoding: &str) -> Option<Compression> {
+        match encoding.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            "bzip2" | "x-bzip2" => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    pub fn sniff(content: &[u8]) -> Option<Compression> {
+        if content.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if content.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if content.starts_with(b"BZh") && content.get(3).is_some_and(u8::is_ascii_digit) {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    pub fn decoder<R: Read + 'static>(&self, content: R) -> Box<dyn Read> {
+        match self {
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(content)),
+            Compression::Zstd => {
+                Box::new(zstd::stream::read::Decoder::new(content).expect("failed to read zstd"))
+            }
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(content)),
+        }
+    }
+}
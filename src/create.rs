@@ -1,25 +1,123 @@
 use crate::data;
+use crate::load;
 use crate::map;
-use clap::{arg, ArgMatches, Command};
+use crate::mapper;
+use arrow::array::RecordBatch;
+use clap::{arg, value_parser, ArgMatches, Command};
 
 pub fn create_cmd() -> Command {
-    Command::new("create")
-        .about("create stub for file")
-        .arg(arg!(<WEBHOOK_TOPIC> "webhook topic").required(true))
-        .arg_required_else_help(true)
+    load::source_args(
+        Command::new("create")
+            .about("create stub for file")
+            .arg(arg!(<WEBHOOK_TOPIC> "webhook topic").required(true))
+            .arg(
+                arg!(--"fetch-size" <ROWS> "rows fetched from the source and sent at a time")
+                    .value_parser(value_parser!(i32).range(1..))
+                    .default_value("10000"),
+            )
+            .arg(arg!(--from <SOURCE> "file, directory, glob pattern or url to read instead of source.sql, or - for stdin")),
+    )
+    .arg_required_else_help(true)
 }
 
 pub fn handler(
     matches: &ArgMatches,
-    db: &mut data::Repository,
+    db: Option<&mut data::Repository>,
     webhook: &mut data::Webhook,
-    mapping: map::Schema,
+    mut mapping: map::Schema,
 ) {
     let topic = matches
         .get_one::<String>("WEBHOOK_TOPIC")
         .expect("required");
 
-    let messages = db.database.to_messages(mapping);
     webhook.hook.set_topic(topic.clone());
-    webhook.hook.send(messages);
+    let sent = match (matches.get_one::<String>("from"), db) {
+        (Some(source), _) => {
+            mapping.csv = mapping.csv.merge(load::csv_dialect_from_args(matches));
+            from_source(source, matches, webhook, &mapping)
+        }
+        (None, Some(db)) => from_database(matches, db, webhook, &mapping),
+        (None, None) => {
+            eprintln!("create needs SERVER and FQN_TABLE, or a source given with --from");
+            return;
+        }
+    };
+    println!("sent {} messages", sent);
+}
+
+fn from_database(
+    matches: &ArgMatches,
+    db: &mut data::Repository,
+    webhook: &mut data::Webhook,
+    mapping: &map::Schema,
+) -> usize {
+    let sql = match mapping.get_sql_source() {
+        Some(x) => x,
+        None => {
+            eprintln!("the mapping has no source.sql, pass --from to read a file or url");
+            return 0;
+        }
+    };
+    let fetch_size = *matches.get_one::<i32>("fetch-size").expect("default");
+    send(
+        db.database.read(sql, fetch_size),
+        webhook,
+        &mut mapper::Mapper::new(mapping),
+    )
+}
+
+// one-shot conversion of a file or url, postgres is never touched
+fn from_source(
+    source: &str,
+    matches: &ArgMatches,
+    webhook: &mut data::Webhook,
+    mapping: &map::Schema,
+) -> usize {
+    let format = load::format_from_args(matches);
+    let sources: Vec<String> = match load::is_many(source) {
+        true => load::expand(source)
+            .iter()
+            .map(|x| x.display().to_string())
+            .collect(),
+        false => vec![source.to_string()],
+    };
+    if sources.is_empty() {
+        eprintln!("no files found for source: {}", source);
+        return 0;
+    }
+
+    // the readers already batch a source, so --fetch-size does not apply here
+    let mut mapper = mapper::Mapper::new(mapping);
+    let mut sent = 0;
+    for source in &sources {
+        match load::open(source, format, matches, mapping) {
+            Some(reader) => {
+                let batches = reader.map(|x| x.map_err(|e| e.to_string()));
+                sent += send(batches, webhook, &mut mapper);
+            }
+            None => eprintln!("failed to read source: {}", source),
+        }
+    }
+    sent
+}
+
+fn send(
+    batches: impl Iterator<Item = Result<RecordBatch, String>>,
+    webhook: &mut data::Webhook,
+    mapper: &mut mapper::Mapper,
+) -> usize {
+    let mut sent = 0;
+    for batch in batches {
+        match batch.and_then(|x| mapper.map(&x)) {
+            Ok(messages) => {
+                sent += messages.len();
+                webhook.hook.send(messages);
+            }
+            Err(e) => {
+                eprintln!("failed to read source: {}", e);
+                break;
+            }
+        }
+    }
+    sent
 }
@@ -1,22 +1,129 @@
 use arrow::array::RecordBatch;
-use postgres::{Error, Row};
-
-use crate::map;
+use arrow::datatypes::SchemaRef;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Serialize, Serializer};
 
 pub struct Repository {
     pub database: Box<dyn Database>,
 }
 
-pub trait Database {
-    fn load(&mut self, batch: RecordBatch);
-    fn query(&mut self, sql: &str) -> Result<Vec<Row>, Error>;
-    fn to_messages(&mut self, schema: map::Schema) -> Vec<Message>;
+// how `load` treats rows already in the destination table
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadMode {
+    Replace,
+    Append,
+    Truncate,
+    Upsert(Vec<String>),
 }
 
+// what `prepare` may do to a table that already exists
 #[derive(Debug, Clone)]
-pub struct Message {
-    pub key: String,
-    pub value: String,
+pub struct LoadOptions {
+    pub mode: LoadMode,
+    pub key: Vec<String>,
+    pub unique: Vec<Vec<String>>,
+    pub indexes: Vec<Vec<String>>,
+    pub allow_unsafe: bool,
+    pub dry_run: bool,
+}
+
+// a load runs in one transaction from `prepare` until `commit` or `rollback`
+pub trait Database {
+    fn prepare(&mut self, schema: SchemaRef, options: &LoadOptions) -> Result<(), String>;
+    // first_row is where the batch starts in its source, for error messages
+    fn load(&mut self, batch: RecordBatch, first_row: usize) -> Result<usize, String>;
+    fn commit(&mut self);
+    fn rollback(&mut self);
+    // yields the rows of a query one fetch at a time, named after its columns
+    fn read(
+        &mut self,
+        sql: &str,
+        fetch_size: i32,
+    ) -> Box<dyn Iterator<Item = Result<RecordBatch, String>> + '_>;
+}
+
+// a prop value typed by the mapping's dataType
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    // kept as text so no precision is lost on the way to a sink
+    Decimal(String),
+    Date(NaiveDate),
+    Timestamp(DateTime<Utc>),
+    Json(serde_json::Value),
+    List(Vec<Value>),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Null => serializer.serialize_none(),
+            Value::Bool(x) => serializer.serialize_bool(*x),
+            Value::Int(x) => serializer.serialize_i64(*x),
+            Value::Float(x) => serializer.serialize_f64(*x),
+            Value::String(x) | Value::Decimal(x) => serializer.serialize_str(x),
+            Value::Date(x) => serializer.collect_str(&x.format("%Y-%m-%d")),
+            Value::Timestamp(x) => {
+                serializer.serialize_str(&x.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            Value::Json(x) => x.serialize(serializer),
+            Value::List(x) => x.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Prop {
+    pub label: String,
+    pub data_type: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entity {
+    pub fqn: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub sub_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_id: Option<String>,
+    pub source_id: String,
+    pub props: Vec<Prop>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Relationship {
+    // the mapping reference, e.g. PERSON.Person!0-LOCATION.Address
+    #[serde(skip)]
+    pub reference: String,
+    pub rel_type: String,
+    pub from_id: String,
+    pub to_id: String,
+    pub props: Vec<Prop>,
+}
+
+// sinks decide how a message is encoded, the key is the same for all of them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Message {
+    Entity(Entity),
+    Relationship(Relationship),
+}
+
+impl Message {
+    pub fn key(&self) -> String {
+        match self {
+            Message::Entity(x) => format!("{}.{}.{}", x.type_, x.sub_type, x.source_id),
+            Message::Relationship(x) => format!("{}.{}-{}", x.reference, x.from_id, x.to_id),
+        }
+    }
 }
 
 pub struct Webhook {
@@ -0,0 +1,326 @@
+use clap::ArgMatches;
+use regex::{Captures, Regex};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use std::env;
+use std::io::{self, IsTerminal, Read};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+pub enum Auth {
+    Bearer(String),
+    Basic(String, String),
+}
+
+pub struct Options {
+    pub headers: HeaderMap,
+    pub auth: Option<Auth>,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            headers: HeaderMap::new(),
+            auth: None,
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+// replace ${NAME} with the value of the NAME environment variable
+fn expand_env(value: &str) -> Result<String, String> {
+    let pattern = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut missing: Vec<String> = vec![];
+    let expanded = pattern.replace_all(value, |caps: &Captures| {
+        env::var(&caps[1]).unwrap_or_else(|_| {
+            missing.push(caps[1].to_string());
+            String::new()
+        })
+    });
+
+    if missing.is_empty() {
+        Ok(expanded.to_string())
+    } else {
+        Err(format!(
+            "environment variables not set: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
+pub fn from_args(matches: &ArgMatches) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    if let Some(headers) = matches.get_many::<String>("header") {
+        for header in headers {
+            let (name, value) = header
+                .split_once(':')
+                .ok_or(format!("expected NAME: VALUE, got {}", header))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("invalid header name: {}", name))?;
+            let mut value = HeaderValue::from_str(&expand_env(value.trim())?)
+                .map_err(|_| format!("invalid value for header {}", name))?;
+            value.set_sensitive(true);
+            options.headers.append(name, value);
+        }
+    }
+
+    if let Some(var) = matches.get_one::<String>("bearer-env") {
+        let token = env::var(var).map_err(|_| format!("environment variable not set: {}", var))?;
+        options.auth = Some(Auth::Bearer(token));
+    }
+
+    if let Some(credentials) = matches.get_one::<String>("basic-auth") {
+        let (user, password) = credentials
+            .split_once(':')
+            .ok_or("expected USER:PASSWORD for basic auth")?;
+        options.auth = Some(Auth::Basic(expand_env(user)?, expand_env(password)?));
+    }
+
+    if let Some(x) = matches.get_one::<u64>("timeout") {
+        options.timeout = Duration::from_secs(*x);
+    }
+
+    if let Some(x) = matches.get_one::<u32>("retries") {
+        options.retries = *x;
+    }
+
+    Ok(options)
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// retries 5xx, 429 and connection failures with exponential backoff, any other
+// non-success status fails straight away
+pub fn get(url: &Url, options: &Options) -> Result<Response, String> {
+    let client = Client::builder()
+        .default_headers(options.headers.clone())
+        .timeout(options.timeout)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut attempt = 0;
+    loop {
+        let mut request = client.get(url.clone());
+        request = match &options.auth {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic(user, password)) => request.basic_auth(user, Some(password)),
+            None => request,
+        };
+
+        let error = match request.send() {
+            Ok(x) if x.status().is_success() => return Ok(x),
+            Ok(x) if is_retryable(x.status()) => format!("server returned {}", x.status()),
+            Ok(x) => return Err(format!("server returned {}", x.status())),
+            Err(e) if e.is_connect() || e.is_timeout() => e.to_string(),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        if attempt >= options.retries {
+            return Err(format!("{} after {} attempts", error, attempt + 1));
+        }
+
+        let delay = options.backoff * 2u32.pow(attempt);
+        eprintln!("request failed: {}, retrying in {:?}", error, delay);
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+// reports download progress on stderr when the server sends a Content-Length
+pub struct Progress<R> {
+    inner: R,
+    read: u64,
+    total: Option<u64>,
+    percent: u64,
+}
+
+impl<R: Read> Progress<R> {
+    pub fn new(inner: R, total: Option<u64>) -> Self {
+        // a progress line only makes sense on a terminal
+        let total = total.filter(|x| *x > 0 && io::stderr().is_terminal());
+        Progress {
+            inner,
+            read: 0,
+            total,
+            percent: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+
+        if let Some(total) = self.total {
+            let percent = (self.read * 100 / total).min(100);
+            if percent != self.percent || n == 0 {
+                self.percent = percent;
+                eprint!(
+                    "\rdownloaded {:.1} / {:.1} MiB ({}%)",
+                    self.read as f64 / 1048576.0,
+                    total as f64 / 1048576.0,
+                    percent
+                );
+                if n == 0 {
+                    eprintln!();
+                    self.total = None;
+                }
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Command;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // answers one connection per status in turn, counting the requests it saw
+    fn serve(statuses: &[u16]) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/data.csv",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let statuses = statuses.to_vec();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let body = "a,b\n1,2\n";
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn options(retries: u32) -> Options {
+        Options {
+            retries,
+            backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        crate::load::source_args(Command::new("test"))
+            .try_get_matches_from([&["test"], args].concat())
+            .unwrap()
+    }
+
+    #[test]
+    fn get_retries_server_errors() {
+        let (url, requests) = serve(&[503, 503, 200]);
+        let mut body = String::new();
+        get(&url, &options(3))
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "a,b\n1,2\n");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn get_fails_fast_on_client_errors() {
+        let (url, requests) = serve(&[404, 200]);
+        let error = get(&url, &options(3)).err().unwrap();
+        assert_eq!(error, "server returned 404 Not Found");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn get_stops_after_retries() {
+        let (url, requests) = serve(&[503, 429, 503, 200]);
+        let error = get(&url, &options(2)).err().unwrap();
+        assert_eq!(
+            error,
+            "server returned 503 Service Unavailable after 3 attempts"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn expand_env_replaces_variables() {
+        env::set_var("EM_TEST_HTTP_TOKEN", "secret");
+        assert_eq!(
+            expand_env("Bearer ${EM_TEST_HTTP_TOKEN}").unwrap(),
+            "Bearer secret"
+        );
+        assert_eq!(
+            expand_env("$EM_TEST_HTTP_TOKEN {x}").unwrap(),
+            "$EM_TEST_HTTP_TOKEN {x}"
+        );
+        assert_eq!(
+            expand_env("${EM_TEST_HTTP_UNSET_A}:${EM_TEST_HTTP_UNSET_B}")
+                .err()
+                .unwrap(),
+            "environment variables not set: EM_TEST_HTTP_UNSET_A, EM_TEST_HTTP_UNSET_B"
+        );
+    }
+
+    #[test]
+    fn from_args_parses_headers() {
+        env::set_var("EM_TEST_HTTP_KEY", "abc");
+        let options = from_args(&matches(&[
+            "--header",
+            "X-Api-Key: ${EM_TEST_HTTP_KEY}",
+            "--header",
+            "Accept:text/csv",
+            "--header",
+            "accept: application/json",
+            "--retries",
+            "5",
+        ]))
+        .unwrap();
+        assert_eq!(options.headers["x-api-key"], "abc");
+        assert!(options.headers["x-api-key"].is_sensitive());
+        let accept: Vec<&HeaderValue> = options.headers.get_all("accept").iter().collect();
+        assert_eq!(accept, ["text/csv", "application/json"]);
+        assert_eq!(options.retries, 5);
+    }
+
+    #[test]
+    fn from_args_rejects_bad_headers() {
+        let error = |x: &str| from_args(&matches(&["--header", x])).err().unwrap();
+        assert_eq!(error("no-colon"), "expected NAME: VALUE, got no-colon");
+        assert_eq!(error("bad name: x"), "invalid header name: bad name");
+        assert_eq!(
+            error("X-Key: ${EM_TEST_HTTP_UNSET_C}"),
+            "environment variables not set: EM_TEST_HTTP_UNSET_C"
+        );
+    }
+}
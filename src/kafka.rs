@@ -37,8 +37,11 @@ pub fn from_config(file_path: std::path::PathBuf) -> data::Webhook {
 
 impl data::Hook for Provider {
     fn _send(&self, msg: data::Message) -> bool {
+        // kafka consumers read messages as json
         let topic = self.get_topic();
-        let record = FutureRecord::to(&topic).key(&msg.key).payload(&msg.value);
+        let key = msg.key();
+        let payload = serde_json::to_vec(&msg).expect("failed to encode message");
+        let record = FutureRecord::to(&topic).key(&key).payload(&payload);
         block_on(self.producer.send(record, Duration::from_secs(0))).expect("failed to deliver");
 
         true
@@ -1,101 +1,777 @@
+use crate::compression::Compression;
 use crate::data;
+use crate::http;
 use crate::map;
-use arrow::array::RecordBatch;
-use clap::{arg, ArgMatches, Command};
-use reqwest::header::CONTENT_TYPE;
-use std::fs;
-use std::io::Cursor;
-use std::path::Path;
+use arrow::array::{Array, RecordBatch, RecordBatchIterator, RecordBatchReader};
+use arrow::datatypes::{Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::{FileReader, StreamReader};
+use arrow_cast::can_cast_types;
+use bytes::Bytes;
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+use mime::Mime;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use parquet::file::reader::ChunkReader;
+use regex::Regex;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use url::Url;
 
 pub fn create_cmd() -> Command {
-    Command::new("load")
-        .about("load file to database")
-        .arg(arg!(<SOURCE> "file to load or url"))
-        .arg_required_else_help(true)
+    source_args(
+        Command::new("load")
+            .about("load file to database")
+            .arg(arg!(<SOURCE> "file, directory, glob pattern or url to load, or - for stdin"))
+            .arg(
+                arg!(--mode <MODE> "how to treat rows already in the table")
+                    .value_parser(["replace", "append", "truncate", "upsert"])
+                    .default_value("replace"),
+            ),
+    )
+    .arg(arg!(--"allow-unsafe-changes" "allow narrowing types and dropping columns of an existing table"))
+    .arg(arg!(--"dry-run" "print the planned schema changes without loading"))
+    .arg_required_else_help(true)
 }
 
-pub fn handle_file(path: &Path, mapping: &map::Schema) -> Option<RecordBatch> {
+// format, csv and http options for commands that read a source through `open`
+pub fn source_args(cmd: Command) -> Command {
+    cmd.arg(
+        arg!(--format <FORMAT> "source format, overrides the extension or content type")
+            .value_parser(["csv", "json", "jsonl", "parquet", "arrow"]),
+    )
+    .arg(arg!(--delimiter <CHAR> "csv field delimiter").value_parser(parse_char))
+    .arg(arg!(--quote <CHAR> "csv quote character").value_parser(parse_char))
+    .arg(arg!(--escape <CHAR> "csv escape character").value_parser(parse_char))
+    .arg(arg!(--"no-header" "csv has no header row"))
+    .arg(arg!(--comment <CHAR> "csv comment line prefix").value_parser(parse_char))
+    .arg(
+        arg!(--null <MARKER> "csv value to read as null, may be repeated")
+            .action(ArgAction::Append),
+    )
+    .arg(
+        arg!(--"skip-rows" <N> "csv lines to skip before the header, or before the data with --no-header")
+            .value_parser(value_parser!(usize)),
+    )
+    .arg(
+        arg!(--header <HEADER> "http header as NAME: VALUE, ${VAR} reads the environment")
+            .action(ArgAction::Append),
+    )
+    .arg(arg!(--"bearer-env" <VAR> "environment variable holding an http bearer token"))
+    .arg(arg!(--"basic-auth" <CREDENTIALS> "http basic auth as USER:PASSWORD, ${VAR} reads the environment"))
+    .arg(
+        arg!(--timeout <SECONDS> "http request timeout")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        arg!(--retries <N> "http retries on server and connection errors")
+            .value_parser(value_parser!(u32)),
+    )
+}
+
+// accepts a single ascii character, or `\t` / `tab` since a raw tab is awkward to type
+fn parse_char(value: &str) -> Result<char, String> {
+    match value {
+        "\\t" | "tab" => Ok('\t'),
+        x if x.len() == 1 && x.is_ascii() => Ok(x.chars().next().unwrap()),
+        _ => Err("expected a single ascii character".to_string()),
+    }
+}
+
+fn load_mode_from_args(matches: &ArgMatches, mapping: &map::Schema) -> Option<data::LoadMode> {
+    match matches.get_one::<String>("mode").map(|x| x.as_str()) {
+        Some("append") => Some(data::LoadMode::Append),
+        Some("truncate") => Some(data::LoadMode::Truncate),
+        Some("upsert") => {
+            if mapping.key.is_empty() {
+                eprintln!("upsert needs a key in the mapping");
+                return None;
+            }
+
+            Some(data::LoadMode::Upsert(mapping.key.clone()))
+        }
+        _ => Some(data::LoadMode::Replace),
+    }
+}
+
+pub fn csv_dialect_from_args(matches: &ArgMatches) -> map::CsvDialect {
+    map::CsvDialect {
+        delimiter: matches.get_one::<char>("delimiter").copied(),
+        quote: matches.get_one::<char>("quote").copied(),
+        escape: matches.get_one::<char>("escape").copied(),
+        header: matches.get_flag("no-header").then_some(false),
+        comment: matches.get_one::<char>("comment").copied(),
+        nullValues: matches
+            .get_many::<String>("null")
+            .map(|x| x.cloned().collect()),
+        skipRows: matches.get_one::<usize>("skip-rows").copied(),
+    }
+}
+
+// readers yield batches lazily so a source is never held in memory whole
+pub type Batches = Box<dyn RecordBatchReader>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+    Parquet,
+    Arrow,
+}
+
+impl Format {
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match ext {
+            "csv" => Some(Format::Csv),
+            "json" | "jsonl" => Some(Format::Json),
+            "parquet" => Some(Format::Parquet),
+            "arrow" | "feather" | "ipc" => Some(Format::Arrow),
+            _ => None,
+        }
+    }
+
+    // generic types such as text/plain and application/octet-stream say nothing
+    pub fn from_media_type(media_type: &Mime) -> Option<Format> {
+        match media_type.essence_str() {
+            "text/csv"
+            | "application/csv"
+            | "text/comma-separated-values"
+            | "text/x-comma-separated-values" => Some(Format::Csv),
+            "application/json" | "text/json" => Some(Format::Json),
+            "application/x-ndjson"
+            | "application/ndjson"
+            | "application/jsonl"
+            | "application/x-jsonlines"
+            | "application/jsonlines" => Some(Format::Json),
+            "application/vnd.apache.parquet" | "application/x-parquet" | "application/parquet" => {
+                Some(Format::Parquet)
+            }
+            "application/vnd.apache.arrow.file"
+            | "application/vnd.apache.arrow.stream"
+            | "application/x-arrow" => Some(Format::Arrow),
+            _ => None,
+        }
+    }
+
+    // data.csv.gz is read as data.csv
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let path = match path
+            .extension()
+            .and_then(|x| x.to_str())
+            .and_then(Compression::from_extension)
+        {
+            Some(_) => path.file_stem().map(Path::new)?,
+            None => path,
+        };
+
+        path.extension()
+            .and_then(|x| x.to_str())
+            .and_then(Format::from_extension)
+    }
+
+    // last resort when neither headers nor a file name give the format away
+    pub fn sniff(content: &[u8]) -> Format {
+        if content.starts_with(b"PAR1") {
+            return Format::Parquet;
+        }
+
+        if content.starts_with(b"ARROW1") || content.starts_with(&[0xff, 0xff, 0xff, 0xff]) {
+            return Format::Arrow;
+        }
+
+        match content.iter().find(|x| !x.is_ascii_whitespace()) {
+            Some(b'{') | Some(b'[') => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+}
+
+pub fn handle_file(path: &Path, format: Option<Format>, mapping: &map::Schema) -> Option<Batches> {
     if !path.is_file() {
-        eprintln!("failed to find file")
+        eprintln!("failed to find file");
+        return None;
     }
 
-    let buf = fs::read(path).unwrap();
+    let file = File::open(path).expect("failed to open file");
 
-    match path.extension() {
-        Some(x) if x == "csv" => handle_csv(buf, mapping),
-        Some(x) if x == "json" => handle_json(buf, mapping),
-        Some(x) if x == "jsonl" => handle_json(buf, mapping),
-        Some(_) => unimplemented!(),
-        None => None,
+    let compression = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .and_then(Compression::from_extension);
+    let format = format.or_else(|| Format::from_path(path));
+
+    match (format, compression) {
+        (None, _) => {
+            eprintln!(
+                "unknown format for {}, pass --format to set one",
+                path.display()
+            );
+            None
+        }
+        (Some(x), Some(c)) => handle_stream(x, c.decoder(file), mapping),
+        (Some(Format::Csv), None) => handle_csv(file, mapping),
+        (Some(Format::Json), None) => handle_json(file, mapping),
+        (Some(Format::Parquet), None) => handle_parquet(file, mapping),
+        (Some(Format::Arrow), None) => handle_ipc(BufReader::new(file), mapping),
     }
 }
 
-fn handle_csv(content: Vec<u8>, mapping: &map::Schema) -> Option<RecordBatch> {
-    let buf = Cursor::new(content);
-    let mut csv = arrow_csv::reader::ReaderBuilder::new(Arc::new(mapping.fields.clone()))
-        .with_header(true)
-        .with_escape(b'"')
-        .build(buf)
-        .unwrap();
+// sources that can only be read front to back, such as stdin or a decompressor
+fn handle_stream<R: Read + 'static>(
+    format: Format,
+    content: R,
+    mapping: &map::Schema,
+) -> Option<Batches> {
+    match format {
+        Format::Csv => handle_csv(content, mapping),
+        Format::Json => handle_json(content, mapping),
+        Format::Parquet => handle_parquet(Bytes::from(read_all(content)), mapping),
+        Format::Arrow => handle_ipc_stream(content, mapping),
+    }
+}
 
-    Some(csv.next().unwrap().unwrap())
+fn read_all<R: Read>(mut content: R) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![];
+    content
+        .read_to_end(&mut buf)
+        .expect("failed to read source");
+    buf
 }
 
-fn handle_json(content: Vec<u8>, mapping: &map::Schema) -> Option<RecordBatch> {
-    let buf = Cursor::new(content);
-    let mut csv = arrow_json::reader::ReaderBuilder::new(Arc::new(mapping.fields.clone()))
-        .build(buf)
+fn skip_lines<R: Read>(content: R, lines: usize) -> io::Result<BufReader<R>> {
+    let mut content = BufReader::new(content);
+    let mut line: Vec<u8> = vec![];
+    for _ in 0..lines {
+        line.clear();
+        if content.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+    }
+    Ok(content)
+}
+
+fn handle_csv<R: Read + 'static>(content: R, mapping: &map::Schema) -> Option<Batches> {
+    let dialect = &mapping.csv;
+    let header = dialect.header.unwrap_or(true);
+    let mut builder = arrow_csv::reader::ReaderBuilder::new(read_schema(mapping))
+        .with_header(header)
+        .with_delimiter(dialect.delimiter.map_or(b',', |x| x as u8))
+        .with_quote(dialect.quote.map_or(b'"', |x| x as u8));
+
+    // without an escape character a doubled quote stands for a literal one
+    if let Some(x) = dialect.escape {
+        builder = builder.with_escape(x as u8);
+    }
+
+    if let Some(x) = dialect.comment {
+        builder = builder.with_comment(x as u8);
+    }
+
+    if let Some(x) = &dialect.nullValues {
+        let markers: Vec<String> = x.iter().map(|x| regex::escape(x)).collect();
+        let null_regex = Regex::new(&format!("^(?:{})$", markers.join("|")))
+            .expect("failed to build null regex");
+        builder = builder.with_null_regex(null_regex);
+    }
+
+    // skipped rows come before the header, so a preamble never reaches the parser
+    let content = match skip_lines(content, dialect.skipRows.unwrap_or(0)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("failed to read source: {}", e);
+            return None;
+        }
+    };
+
+    let csv = builder.build(content).unwrap();
+
+    Some(Box::new(csv))
+}
+
+fn handle_json<R: Read + 'static>(content: R, mapping: &map::Schema) -> Option<Batches> {
+    let json = arrow_json::reader::ReaderBuilder::new(read_schema(mapping))
+        .build(BufReader::new(content))
         .unwrap();
 
-    Some(csv.next().unwrap().unwrap())
-}
-
-fn handle_url(path: Url, mapping: &map::Schema) -> Option<RecordBatch> {
-    if let Ok(mut x) = reqwest::blocking::get(path) {
-        let mut buf: Vec<u8> = vec![];
-        let _ = x.copy_to(&mut buf);
-
-        let content_type_str = x
-            .headers()
-            .get(CONTENT_TYPE)
-            .expect("invalid http headers")
-            .to_str()
-            .expect("invalid utf-8")
-            .split_once(";")
-            .expect("found type")
-            .0
-            .split_once("/")
-            .expect("found type")
-            .1;
-
-        match content_type_str {
-            "csv" => handle_csv(buf, mapping),
-            "plain" => handle_csv(buf, mapping),
-            "json" => handle_json(buf, mapping),
-            _ => unimplemented!(),
+    Some(Box::new(json))
+}
+
+fn handle_parquet<R: ChunkReader + 'static>(content: R, mapping: &map::Schema) -> Option<Batches> {
+    let builder =
+        ParquetRecordBatchReaderBuilder::try_new(content).expect("failed to read parquet");
+    let file_schema = builder.schema().clone();
+    if !check_schema(&file_schema, mapping) {
+        return None;
+    }
+
+    // only decode the columns the mapping asks for
+    let indices: Vec<usize> = mapping
+        .fields
+        .fields()
+        .iter()
+        .map(|field| file_schema.index_of(field.name()).unwrap())
+        .collect();
+
+    let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+    let parquet = builder
+        .with_projection(mask)
+        .build()
+        .expect("failed to read parquet");
+
+    Some(conform(parquet, mapping))
+}
+
+fn handle_ipc<R: Read + Seek + 'static>(mut content: R, mapping: &map::Schema) -> Option<Batches> {
+    // the file format opens with a magic string, the stream format does not
+    let mut magic = [0u8; 6];
+    let is_file = content.read_exact(&mut magic).is_ok() && &magic == b"ARROW1";
+    content.rewind().expect("failed to rewind source");
+
+    if is_file {
+        let ipc = FileReader::try_new(content, None).expect("failed to read arrow file");
+        check_and_conform(Box::new(ipc), mapping)
+    } else {
+        let ipc = StreamReader::try_new(content, None).expect("failed to read arrow stream");
+        check_and_conform(Box::new(ipc), mapping)
+    }
+}
+
+fn handle_ipc_stream<R: Read + 'static>(content: R, mapping: &map::Schema) -> Option<Batches> {
+    let mut content = BufReader::new(content);
+    let is_file = content
+        .fill_buf()
+        .expect("failed to read source")
+        .starts_with(b"ARROW1");
+
+    // the file format keeps its footer at the end so it has to be buffered
+    if is_file {
+        return handle_ipc(Cursor::new(read_all(content)), mapping);
+    }
+
+    let ipc = StreamReader::try_new(content, None).expect("failed to read arrow stream");
+    check_and_conform(Box::new(ipc), mapping)
+}
+
+fn check_and_conform(batches: Batches, mapping: &map::Schema) -> Option<Batches> {
+    if !check_schema(&batches.schema(), mapping) {
+        return None;
+    }
+
+    Some(conform(batches, mapping))
+}
+
+// report every mapped column a self-describing source lacks or cannot be cast from
+fn check_schema(source: &Schema, mapping: &map::Schema) -> bool {
+    let mut missing: Vec<&str> = vec![];
+    let mut incompatible: Vec<String> = vec![];
+    mapping
+        .fields
+        .fields()
+        .iter()
+        .for_each(|field| match source.field_with_name(field.name()) {
+            Ok(x) if can_cast_types(x.data_type(), field.data_type()) => {}
+            Ok(x) => incompatible.push(format!(
+                "{} ({} -> {})",
+                field.name(),
+                x.data_type(),
+                field.data_type()
+            )),
+            Err(_) => missing.push(field.name()),
+        });
+
+    if !missing.is_empty() {
+        eprintln!("source is missing mapped columns: {}", missing.join(", "));
+    }
+
+    if !incompatible.is_empty() {
+        eprintln!(
+            "source has columns that cannot be cast: {}",
+            incompatible.join(", ")
+        );
+    }
+
+    missing.is_empty() && incompatible.is_empty()
+}
+
+// sources are read with every field nullable so `require` can name the offending row
+fn read_schema(mapping: &map::Schema) -> SchemaRef {
+    let fields: Vec<Field> = mapping
+        .fields
+        .fields()
+        .iter()
+        .map(|x| x.as_ref().clone().with_nullable(true))
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+// fail on the first null in a required field, then restore the mapping's nullability
+fn require(batches: Batches, mapping: &map::Schema) -> Batches {
+    let schema = Arc::new(mapping.fields.clone());
+    let required = schema.clone();
+    let mut offset = 0;
+    Box::new(RecordBatchIterator::new(
+        batches.map(move |batch| {
+            let batch = batch?;
+            for (field, column) in required.fields().iter().zip(batch.columns()) {
+                if field.is_nullable() || column.null_count() == 0 {
+                    continue;
+                }
+
+                let row = (0..column.len()).find(|x| column.is_null(*x)).unwrap();
+                return Err(ArrowError::InvalidArgumentError(format!(
+                    "row {}: required field {} is null",
+                    offset + row + 1,
+                    field.name()
+                )));
+            }
+
+            offset += batch.num_rows();
+            RecordBatch::try_new(required.clone(), batch.columns().to_vec())
+        }),
+        schema,
+    ))
+}
+
+fn conform<I>(batches: I, mapping: &map::Schema) -> Batches
+where
+    I: Iterator<Item = Result<RecordBatch, ArrowError>> + 'static,
+{
+    let schema = read_schema(mapping);
+    let conformed = schema.clone();
+    Box::new(RecordBatchIterator::new(
+        batches.map(move |batch| batch.and_then(|x| cast_to_mapping(&x, &conformed))),
+        schema,
+    ))
+}
+
+// reorder and cast a batch so it lines up with the mapping's arrow schema
+fn cast_to_mapping(batch: &RecordBatch, schema: &SchemaRef) -> Result<RecordBatch, ArrowError> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let column = batch.column_by_name(field.name()).ok_or_else(|| {
+                ArrowError::SchemaError(format!("missing column: {}", field.name()))
+            })?;
+            arrow_cast::cast(column, field.data_type())
+        })
+        .collect::<Result<Vec<_>, ArrowError>>()?;
+
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+fn handle_url(
+    path: Url,
+    format: Option<Format>,
+    options: &http::Options,
+    mapping: &map::Schema,
+) -> Option<Batches> {
+    let response = match http::get(&path, options) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("failed to fetch {}: {}", path, e);
+            return None;
         }
+    };
+
+    let headers = response.headers().clone();
+    let total = response.content_length();
+    let mut content = BufReader::new(http::Progress::new(response, total));
+
+    // servers often send .gz files as plain bytes, so fall back to magic numbers
+    let compression = headers
+        .get(CONTENT_ENCODING)
+        .and_then(|x| x.to_str().ok())
+        .and_then(Compression::from_encoding)
+        .or_else(|| Compression::sniff(content.fill_buf().expect("failed to read response")));
+    let content: Box<dyn Read> = match compression {
+        Some(c) => c.decoder(content),
+        None => Box::new(content),
+    };
+    let mut content = BufReader::new(content);
+
+    let format = format
+        .or_else(|| {
+            headers
+                .get(CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse::<Mime>().ok())
+                .and_then(|x| Format::from_media_type(&x))
+        })
+        .or_else(|| {
+            path.path_segments()
+                .and_then(|mut x| x.next_back())
+                .and_then(|x| Format::from_path(Path::new(x)))
+        })
+        .unwrap_or_else(|| Format::sniff(content.fill_buf().expect("failed to read response")));
+
+    handle_stream(format, content, mapping)
+}
+
+// expand a directory or glob pattern into its files, in a stable order
+pub fn expand(source: &str) -> Vec<PathBuf> {
+    let path = Path::new(source);
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        fs::read_dir(path)
+            .expect("failed to read directory")
+            .filter_map(|x| x.ok().map(|x| x.path()))
+            .collect()
     } else {
-        None
+        glob::glob(source)
+            .expect("invalid glob pattern")
+            .filter_map(|x| x.ok())
+            .collect()
+    };
+
+    // skip marker and hidden files such as _SUCCESS and .part.crc
+    files.retain(|x| {
+        x.is_file()
+            && !x
+                .file_name()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| x.starts_with('.') || x.starts_with('_'))
+    });
+    files.sort();
+    files
+}
+
+// urls can carry '?' in their query string, so they never count as patterns
+fn is_pattern(source: &str) -> bool {
+    source.contains(['*', '?', '[']) && Url::parse(source).is_err()
+}
+
+fn load_batches(repo: &mut data::Repository, reader: Batches) -> Result<usize, ArrowError> {
+    let mut rows = 0;
+    let mut read = 0;
+    for batch in reader {
+        let batch = batch?;
+        let size = batch.num_rows();
+        rows += repo
+            .database
+            .load(batch, read)
+            .map_err(ArrowError::InvalidArgumentError)?;
+        read += size;
     }
+    Ok(rows)
 }
 
-pub fn handler(matches: &ArgMatches, repo: &mut data::Repository, mapping: map::Schema) {
-    let source = matches.get_one::<String>("SOURCE").expect("required");
+// false when nothing should be loaded, either because the table could not be
+// brought in line with the mapping or because this is a dry run
+fn prepare(repo: &mut data::Repository, schema: SchemaRef, options: &data::LoadOptions) -> bool {
+    match repo.database.prepare(schema, options) {
+        Ok(()) if options.dry_run => {
+            repo.database.rollback();
+            false
+        }
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("failed to prepare table: {}", e);
+            repo.database.rollback();
+            false
+        }
+    }
+}
+
+fn handle_many(
+    source: &str,
+    format: Option<Format>,
+    options: &data::LoadOptions,
+    repo: &mut data::Repository,
+    mapping: &map::Schema,
+) {
+    let files = expand(source);
+    if files.is_empty() {
+        eprintln!("no files found for source: {}", source);
+        return;
+    }
+
+    if !prepare(repo, Arc::new(mapping.fields.clone()), options) {
+        return;
+    }
+    let mut rows = 0;
+    for file in &files {
+        let loaded = match handle_file(file, format, mapping) {
+            Some(reader) => load_batches(repo, require(reader, mapping)),
+            None => Err(ArrowError::InvalidArgumentError(
+                "unreadable source".to_string(),
+            )),
+        };
+
+        // one bad file rolls back every file loaded before it
+        match loaded {
+            Ok(x) => {
+                println!("loaded {} rows from {}", x, file.display());
+                rows += x;
+            }
+            Err(e) => {
+                eprintln!("failed to load {}: {}", file.display(), e);
+                repo.database.rollback();
+                return;
+            }
+        }
+    }
+
+    repo.database.commit();
+    println!("loaded {} rows from {} files", rows, files.len());
+}
+
+pub fn format_from_args(matches: &ArgMatches) -> Option<Format> {
+    matches
+        .get_one::<String>("format")
+        .and_then(|x| Format::from_extension(x))
+}
+
+// a directory or a glob pattern stands for every file it expands to
+pub fn is_many(source: &str) -> bool {
     let path = Path::new(source);
+    path.is_dir() || (!path.exists() && is_pattern(source))
+}
 
-    let batch: Option<RecordBatch> = if path.exists() {
-        handle_file(path, &mapping)
+// reads a single file, url or stdin source as batches of the mapping's fields
+pub fn open(
+    source: &str,
+    format: Option<Format>,
+    matches: &ArgMatches,
+    mapping: &map::Schema,
+) -> Option<Batches> {
+    let path = Path::new(source);
+    if source == "-" {
+        match format {
+            Some(x) => handle_stream(x, io::stdin(), mapping),
+            None => {
+                eprintln!("--format is required when reading from stdin");
+                None
+            }
+        }
+    } else if path.exists() {
+        handle_file(path, format, mapping)
     } else {
-        match Url::parse(source) {
-            Ok(x) => handle_url(x, &mapping),
-            Err(_) => None,
+        match (Url::parse(source), http::from_args(matches)) {
+            (Ok(x), Ok(options)) => handle_url(x, format, &options, mapping),
+            (Ok(_), Err(e)) => {
+                eprintln!("invalid http options: {}", e);
+                None
+            }
+            (Err(_), _) => None,
         }
+    }
+}
+
+pub fn handler(matches: &ArgMatches, repo: &mut data::Repository, mut mapping: map::Schema) {
+    mapping.csv = mapping.csv.merge(csv_dialect_from_args(matches));
+    let unknown = mapping.unknown_columns();
+    if !unknown.is_empty() {
+        eprintln!(
+            "keys and indexes refer to unmapped fields: {}",
+            unknown.join(", ")
+        );
+        return;
+    }
+
+    let options = match load_mode_from_args(matches, &mapping) {
+        Some(mode) => data::LoadOptions {
+            mode,
+            key: mapping.key.clone(),
+            unique: mapping.unique.clone(),
+            indexes: mapping.indexes.clone(),
+            allow_unsafe: matches.get_flag("allow-unsafe-changes"),
+            dry_run: matches.get_flag("dry-run"),
+        },
+        None => return,
     };
 
-    match batch {
-        Some(x) => repo.database.load(x),
+    let source = matches.get_one::<String>("SOURCE").expect("required");
+    let format = format_from_args(matches);
+    if is_many(source) {
+        return handle_many(source, format, &options, repo, &mapping);
+    }
+
+    let batches = open(source, format, matches, &mapping);
+    match batches {
+        Some(reader) => {
+            let reader = require(reader, &mapping);
+            if !prepare(repo, reader.schema(), &options) {
+                return;
+            }
+            match load_batches(repo, reader) {
+                Ok(rows) => {
+                    repo.database.commit();
+                    println!("loaded {} rows", rows);
+                }
+                Err(e) => {
+                    eprintln!("failed to load source: {}", e);
+                    repo.database.rollback();
+                }
+            }
+        }
         None => eprintln!("failed to load source"),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::AsArray;
+
+    const MAPPING: &str = "
+fields:
+- label: id
+  dataType: String
+  reference: THING.Thing
+- label: note
+  dataType: String
+  reference: THING.Thing
+";
+
+    // the values of one column, read through the csv reader with the given dialect
+    fn read_csv(content: &str, csv: &str, column: &str) -> Result<Vec<String>, String> {
+        let mapping = map::from_mapping(format!("{}csv:\n{}", MAPPING, csv).as_bytes());
+        let reader = handle_csv(Cursor::new(content.to_string()), &mapping).unwrap();
+        let mut values = vec![];
+        for batch in reader {
+            let batch = batch.map_err(|e| e.to_string())?;
+            let array = batch.column_by_name(column).unwrap().as_string::<i32>();
+            values.extend(array.iter().map(|x| x.unwrap_or_default().to_string()));
+        }
+        Ok(values)
+    }
+
+    #[test]
+    fn csv_changed_quote_keeps_double_quotes() {
+        let content = "id,note\n1,'he said \"hi\" ok'\n2,'it''s'\n";
+        assert_eq!(
+            read_csv(content, "  quote: \"'\"\n", "note").unwrap(),
+            ["he said \"hi\" ok", "it's"]
+        );
+    }
+
+    #[test]
+    fn csv_escape_applies_when_given() {
+        let content = "id,note\n1,\"a \\\"quoted\\\" word\"\n";
+        assert_eq!(
+            read_csv(content, "  escape: \"\\\\\"\n", "note").unwrap(),
+            ["a \"quoted\" word"]
+        );
+    }
+
+    #[test]
+    fn csv_skip_rows_drops_a_preamble_before_the_header() {
+        let content = "exported 2026-10-18 by report\n\nid,note\n1,a\n2,b\n";
+        assert_eq!(
+            read_csv(content, "  skipRows: 2\n", "id").unwrap(),
+            ["1", "2"]
+        );
+    }
+
+    #[test]
+    fn csv_skip_rows_without_header() {
+        let content = "banner\n1,a\n2,b\n";
+        assert_eq!(
+            read_csv(content, "  skipRows: 1\n  header: false\n", "note").unwrap(),
+            ["a", "b"]
+        );
+    }
+}
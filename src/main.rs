@@ -1,23 +1,29 @@
+use clap::error::ErrorKind;
 use clap::{arg, Command};
 use std::fs::File;
 use std::path;
+mod compression;
 mod create;
 mod data;
+mod http;
 mod kafka;
 mod load;
 mod map;
+mod mapper;
+mod pgconn;
+mod pgcopy;
+mod pgrow;
 mod postgres;
 
 fn cli() -> Command {
     Command::new("em")
         .about("EntityMapper CLI")
+        .override_usage("em [SERVER FQN_TABLE] MAPPING <COMMAND>")
         .subcommand_required(true)
         .arg_required_else_help(true)
-        .arg(arg!(<SERVER> "database url").required(true))
-        .arg(arg!(<USER> "user").required(true))
-        .arg(arg!(<PASSWORD> "password").required(true))
-        .arg(arg!(<FQN_TABLE> "database fully qualified name for destination table").required(true))
-        .arg(arg!(<MAPPING> "mapping file").required(true))
+        .arg(arg!([SERVER] "database host, postgres:// url or key=value conninfo, PG* variables and ~/.pgpass fill in the rest"))
+        .arg(arg!([FQN_TABLE] "database fully qualified name for destination table"))
+        .arg(arg!([MAPPING] "mapping file"))
         .subcommand(load::create_cmd())
         .subcommand(create::create_cmd())
 }
@@ -25,14 +31,40 @@ fn cli() -> Command {
 fn main() {
     let matches = cli().get_matches();
 
-    let mapping_file = matches.get_one::<String>("MAPPING").expect("required");
+    // `create --from` needs no database, so a lone positional is the mapping file
+    let positionals: Vec<&String> = ["SERVER", "FQN_TABLE", "MAPPING"]
+        .iter()
+        .filter_map(|x| matches.get_one::<String>(x))
+        .collect();
+    let (server, mapping_file) = match positionals.as_slice() {
+        [server, fqn_table, mapping] => (Some((*server, *fqn_table)), *mapping),
+        [mapping] => (None, *mapping),
+        _ => {
+            cli()
+                .error(
+                    ErrorKind::WrongNumberOfValues,
+                    "expected SERVER FQN_TABLE MAPPING, or only MAPPING for create --from",
+                )
+                .exit();
+        }
+    };
+
     let mapping = map::from_mapping(File::open(mapping_file).expect("failed to open file"));
-    let db = &mut postgres::from_args(&matches);
-    let wh = &mut kafka::from_config(path::PathBuf::from("hook.yml"));
+    let mut db = match (matches.subcommand(), server) {
+        (Some(("create", x)), _) if x.contains_id("from") => None,
+        (_, Some((server, fqn_table))) => Some(postgres::from_args(server, fqn_table)),
+        _ => None,
+    };
 
     match matches.subcommand() {
-        Some(("load", sub_matches)) => load::handler(sub_matches, db, mapping),
-        Some(("create", sub_matches)) => create::handler(sub_matches, db, wh, mapping),
+        Some(("load", sub_matches)) => match db.as_mut() {
+            Some(db) => load::handler(sub_matches, db, mapping),
+            None => eprintln!("load needs SERVER and FQN_TABLE"),
+        },
+        Some(("create", sub_matches)) => {
+            let wh = &mut kafka::from_config(path::PathBuf::from("hook.yml"));
+            create::handler(sub_matches, db.as_mut(), wh, mapping)
+        }
         _ => unreachable!(),
     }
 }
@@ -1,8 +1,8 @@
-use arrow::datatypes::{DataType, Field};
+use arrow::datatypes::{DataType, Field, TimeUnit};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::fs::File;
+use std::io::Read;
 
 enum SourceKind {
     Sql,
@@ -14,6 +14,11 @@ enum MapFieldType {
     String,
     Float,
     Date,
+    Bool,
+    Timestamp,
+    Decimal,
+    Uuid,
+    Json,
 }
 
 impl MapFieldType {
@@ -23,6 +28,11 @@ impl MapFieldType {
             MapFieldType::String => "String",
             MapFieldType::Float => "Float",
             MapFieldType::Date => "Date",
+            MapFieldType::Bool => "Bool",
+            MapFieldType::Timestamp => "Timestamp",
+            MapFieldType::Decimal => "Decimal",
+            MapFieldType::Uuid => "Uuid",
+            MapFieldType::Json => "Json",
         }
         .to_string()
     }
@@ -32,6 +42,9 @@ impl MapFieldType {
 //   labelOverride: source_id
 //   dataType: String
 //   entity: PERSON.Person!0
+//
+// Decimal takes an optional precision and scale, array makes a list of dataType
+// and required rejects rows where the field is null
 #[derive(Serialize, Deserialize, Debug)]
 #[allow(non_snake_case)]
 pub struct MapField {
@@ -39,6 +52,10 @@ pub struct MapField {
     labelOverride: Option<String>,
     dataType: MapFieldType,
     reference: Option<String>,
+    precision: Option<u8>,
+    scale: Option<i8>,
+    array: Option<bool>,
+    required: Option<bool>,
 }
 
 // - label: PersonResidesAtAddress
@@ -53,11 +70,50 @@ pub struct Relationship {
     pub props: Vec<String>,
 }
 
+// csv:
+//   delimiter: "|"
+//   header: false
+//   nullValues:
+//   - \N
+//   skipRows: 2
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[allow(non_snake_case)]
+pub struct CsvDialect {
+    pub delimiter: Option<char>,
+    pub quote: Option<char>,
+    pub escape: Option<char>,
+    pub header: Option<bool>,
+    pub comment: Option<char>,
+    pub nullValues: Option<Vec<String>>,
+    pub skipRows: Option<usize>,
+}
+
+impl CsvDialect {
+    // settings in `other` win over ours
+    pub fn merge(self, other: CsvDialect) -> CsvDialect {
+        CsvDialect {
+            delimiter: other.delimiter.or(self.delimiter),
+            quote: other.quote.or(self.quote),
+            escape: other.escape.or(self.escape),
+            header: other.header.or(self.header),
+            comment: other.comment.or(self.comment),
+            nullValues: other.nullValues.or(self.nullValues),
+            skipRows: other.skipRows.or(self.skipRows),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Map {
     fields: Vec<MapField>,
     relationships: Option<Vec<Relationship>>,
+    // only `create` without --from needs a source
+    #[serde(default)]
     source: HashMap<String, String>,
+    csv: Option<CsvDialect>,
+    key: Option<Vec<String>>,
+    unique: Option<Vec<Vec<String>>>,
+    indexes: Option<Vec<Vec<String>>>,
 }
 
 #[derive(Debug)]
@@ -66,22 +122,44 @@ pub struct Schema {
     pub fields: arrow::datatypes::Schema,
     pub relationships: Vec<Relationship>,
     pub source: HashMap<String, String>,
+    pub csv: CsvDialect,
+    // primary key, also what upserts match rows on
+    pub key: Vec<String>,
+    pub unique: Vec<Vec<String>>,
+    pub indexes: Vec<Vec<String>>,
 }
 
 impl Schema {
-    fn get_source(&self, key: SourceKind) -> &String {
+    fn get_source(&self, key: SourceKind) -> Option<&String> {
         match key {
-            SourceKind::Sql => self.source.get("sql").unwrap(),
+            SourceKind::Sql => self.source.get("sql"),
         }
     }
 
-    pub fn get_sql_source(&self) -> &String {
+    pub fn get_sql_source(&self) -> Option<&String> {
         self.get_source(SourceKind::Sql)
     }
+
+    // key, unique and index columns that are not mapped fields
+    pub fn unknown_columns(&self) -> Vec<&str> {
+        let mut unknown: Vec<&str> = self
+            .unique
+            .iter()
+            .chain(self.indexes.iter())
+            .chain(std::iter::once(&self.key))
+            .flatten()
+            .filter(|x| self.fields.field_with_name(x).is_err())
+            .map(|x| x.as_str())
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
 }
 
-pub fn from_mapping(file: File) -> Schema {
+pub fn from_mapping<R: Read>(file: R) -> Schema {
     let map: Map = serde_yaml::from_reader(file).expect("failed to serialise yaml");
+    let key = map.key.unwrap_or_default();
     let fields: Vec<Field> = map
         .fields
         .iter()
@@ -98,11 +176,46 @@ pub fn from_mapping(file: File) -> Schema {
                 meta.insert("entity".to_string(), x.clone());
             };
 
-            match dict.dataType {
-                MapFieldType::Int => Field::new(dict.label.clone(), DataType::Int64, false),
-                MapFieldType::Float => Field::new(dict.label.clone(), DataType::Float64, false),
-                MapFieldType::String => Field::new(dict.label.clone(), DataType::Utf8, false),
-                MapFieldType::Date => Field::new(dict.label.clone(), DataType::Date64, false),
+            let data_type = match dict.dataType {
+                MapFieldType::Int => DataType::Int64,
+                MapFieldType::Float => DataType::Float64,
+                MapFieldType::String => DataType::Utf8,
+                MapFieldType::Date => DataType::Date64,
+                MapFieldType::Bool => DataType::Boolean,
+                MapFieldType::Timestamp => {
+                    DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into()))
+                }
+                // like postgres numeric(p), a precision on its own has no fraction digits
+                MapFieldType::Decimal => {
+                    let (precision, scale) = match (dict.precision, dict.scale) {
+                        (None, scale) => (38, scale.unwrap_or(10)),
+                        (Some(precision), scale) => (precision, scale.unwrap_or(0)),
+                    };
+                    if !(1..=38).contains(&precision) {
+                        panic!("{}: decimal precision must be 1 to 38", dict.label);
+                    }
+                    if scale > 0 && scale as u8 > precision {
+                        panic!(
+                            "{}: decimal scale {} is larger than its precision {}",
+                            dict.label, scale, precision
+                        );
+                    }
+                    DataType::Decimal128(precision, scale)
+                }
+                // uuid and json travel as strings, the dataType metadata picks the column type
+                MapFieldType::Uuid => DataType::Utf8,
+                MapFieldType::Json => DataType::Utf8,
+            };
+
+            // primary key columns are never null
+            let nullable = !dict.required.unwrap_or(false) && !key.contains(&dict.label);
+            match dict.array {
+                Some(true) => Field::new_list(
+                    dict.label.clone(),
+                    Field::new_list_field(data_type, true).with_metadata(meta.clone()),
+                    nullable,
+                ),
+                _ => Field::new(dict.label.clone(), data_type, nullable),
             }
             .with_metadata(meta)
         })
@@ -110,7 +223,55 @@ pub fn from_mapping(file: File) -> Schema {
 
     Schema {
         fields: arrow::datatypes::Schema::new(fields),
-        relationships: map.relationships.unwrap(),
+        relationships: map.relationships.unwrap_or_default(),
         source: map.source,
+        csv: map.csv.unwrap_or_default(),
+        key,
+        unique: map.unique.unwrap_or_default(),
+        indexes: map.indexes.unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(options: &str) -> DataType {
+        let yaml = format!(
+            "fields:\n- label: amount\n  dataType: Decimal\n  reference: THING.Thing\n{}",
+            options
+        );
+        from_mapping(yaml.as_bytes())
+            .fields
+            .field(0)
+            .data_type()
+            .clone()
+    }
+
+    #[test]
+    fn decimal_defaults() {
+        assert_eq!(decimal(""), DataType::Decimal128(38, 10));
+        assert_eq!(decimal("  precision: 8\n"), DataType::Decimal128(8, 0));
+        assert_eq!(decimal("  scale: 2\n"), DataType::Decimal128(38, 2));
+        assert_eq!(
+            decimal("  precision: 8\n  scale: 3\n"),
+            DataType::Decimal128(8, 3)
+        );
+        assert_eq!(
+            decimal("  precision: 8\n  scale: -2\n"),
+            DataType::Decimal128(8, -2)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "amount: decimal scale 9 is larger than its precision 8")]
+    fn decimal_scale_above_precision() {
+        decimal("  precision: 8\n  scale: 9\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "amount: decimal precision must be 1 to 38")]
+    fn decimal_precision_above_38() {
+        decimal("  precision: 39\n");
     }
 }
@@ -0,0 +1,474 @@
+use crate::data;
+use crate::map;
+use arrow::array::{Array, ArrayRef, AsArray, RecordBatch};
+use arrow::datatypes::{DataType, Date64Type, Decimal128Type, Float64Type, Int64Type, TimeUnit};
+use arrow::datatypes::{Schema, TimestampMicrosecondType};
+use arrow_cast::display::ArrayFormatter;
+use arrow_cast::{cast_with_options, CastOptions};
+use chrono::DateTime;
+use std::collections::HashMap;
+
+// turns batches from any source into entities and relationships, so a source
+// only has to yield arrow data
+pub struct Mapper<'a> {
+    schema: &'a map::Schema,
+    rows: usize,
+    warned: bool,
+}
+
+impl<'a> Mapper<'a> {
+    pub fn new(schema: &'a map::Schema) -> Mapper<'a> {
+        Mapper {
+            schema,
+            rows: 0,
+            warned: false,
+        }
+    }
+
+    // the batch column for each mapping field, matched on the field's label and then
+    // its labelOverride, so the source's column order does not matter. postgres folds
+    // unquoted names to lowercase, so when neither matches exactly they are matched
+    // ignoring case, as long as only one column fits
+    fn resolve_columns(&mut self, schema: &Schema) -> Result<Vec<usize>, String> {
+        let names: Vec<&String> = schema.fields().iter().map(|x| x.name()).collect();
+        let exact = |name: &str| names.iter().position(|x| *x == name);
+        let folded = |name: &str| -> Vec<usize> {
+            (0..names.len())
+                .filter(|i| names[*i].eq_ignore_ascii_case(name))
+                .collect()
+        };
+
+        // without a reference a field belongs to no entity or relationship
+        let unreferenced: Vec<&str> = self
+            .schema
+            .fields
+            .fields()
+            .iter()
+            .filter(|x| !x.metadata().contains_key("entity"))
+            .map(|x| x.name().as_str())
+            .collect();
+        if !unreferenced.is_empty() {
+            return Err(format!(
+                "mapped fields have no reference: {}",
+                unreferenced.join(", ")
+            ));
+        }
+
+        let mut missing: Vec<&str> = vec![];
+        let mut ambiguous: Vec<String> = vec![];
+        let mut resolved: Vec<usize> = vec![];
+        for field in self.schema.fields.fields() {
+            let label = field.metadata().get("label").map(|x| x.as_str());
+            let found = exact(field.name()).or_else(|| label.and_then(exact));
+            if let Some(x) = found {
+                resolved.push(x);
+                continue;
+            }
+
+            let mut candidates = folded(field.name());
+            if candidates.is_empty() {
+                candidates = label.map(folded).unwrap_or_default();
+            }
+            match candidates.as_slice() {
+                [] => missing.push(field.name()),
+                [x] => resolved.push(*x),
+                xs => ambiguous.push(format!(
+                    "{} ({})",
+                    field.name(),
+                    xs.iter()
+                        .map(|i| names[*i].as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                )),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(format!(
+                "mapped fields missing from the source: {}",
+                missing.join(", ")
+            ));
+        }
+        if !ambiguous.is_empty() {
+            return Err(format!(
+                "mapped fields match more than one column: {}",
+                ambiguous.join(", ")
+            ));
+        }
+
+        // a column the source could not read is only a problem once it is mapped
+        let unreadable: Vec<String> = resolved
+            .iter()
+            .zip(self.schema.fields.fields())
+            .filter_map(|(i, field)| {
+                let column = schema.field(*i);
+                column
+                    .metadata()
+                    .get("unsupported")
+                    .map(|ty| format!("{} (column {} is {})", field.name(), column.name(), ty))
+            })
+            .collect();
+        if !unreadable.is_empty() {
+            return Err(format!(
+                "mapped fields have a type the source cannot read: {}",
+                unreadable.join(", ")
+            ));
+        }
+
+        if !self.warned {
+            self.warned = true;
+            let names = schema.fields();
+            names
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !resolved.contains(i))
+                .for_each(
+                    |(_, x)| match resolved.iter().any(|i| names[*i].name() == x.name()) {
+                        true => eprintln!("ignoring duplicate column {}", x.name()),
+                        false => {
+                            eprintln!("ignoring column {}, it is not in the mapping", x.name())
+                        }
+                    },
+                );
+        }
+
+        Ok(resolved)
+    }
+
+    // fails when the batch cannot be mapped at all, rows that cannot be read as
+    // their dataType are reported and skipped
+    pub fn map(&mut self, batch: &RecordBatch) -> Result<Vec<data::Message>, String> {
+        let columns = self.resolve_columns(&batch.schema())?;
+
+        // cast every column to the type the mapping gives it
+        let options = CastOptions {
+            safe: true,
+            ..Default::default()
+        };
+        let mut conformed: Vec<(ArrayRef, ArrayRef)> = vec![];
+        for (&i, field) in columns.iter().zip(self.schema.fields.fields()) {
+            let column = batch.column(i).clone();
+            let cast = cast_with_options(&column, field.data_type(), &options).map_err(|e| {
+                format!(
+                    "cannot read {} ({}) as {}: {}",
+                    field.name(),
+                    column.data_type(),
+                    field.data_type(),
+                    e
+                )
+            })?;
+            conformed.push((column, cast));
+        }
+
+        let mut messages: Vec<data::Message> = vec![];
+        for row in 0..batch.num_rows() {
+            self.rows += 1;
+            if let Err(e) = self.row_into_messages(&conformed, row, &mut messages) {
+                eprintln!("skipping row {}: {}", self.rows, e);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn row_into_messages(
+        &self,
+        columns: &[(ArrayRef, ArrayRef)],
+        row: usize,
+        messages: &mut Vec<data::Message>,
+    ) -> Result<(), String> {
+        let mut entities: HashMap<String, Vec<data::Prop>> = HashMap::new();
+        let mut entity_ids: HashMap<String, String> = HashMap::new();
+        let mut relationships: HashMap<String, Vec<data::Prop>> = HashMap::new();
+
+        for ((source, column), field) in columns.iter().zip(self.schema.fields.fields()) {
+            let label = field.metadata().get("label").unwrap();
+            let data_type = field.metadata().get("dataType").unwrap();
+
+            // a safe cast turns unreadable values into nulls, which must not pass as missing
+            if source.is_valid(row) && column.is_null(row) {
+                let text = ArrayFormatter::try_new(source.as_ref(), &Default::default())
+                    .map(|x| x.value(row).to_string())
+                    .unwrap_or_default();
+                return Err(format!(
+                    "{}: cannot read {} as {}",
+                    field.name(),
+                    text,
+                    data_type
+                ));
+            }
+
+            let value = value(column.as_ref(), row, data_type);
+            if value == data::Value::Null && !field.is_nullable() {
+                return Err(format!("required field {} is null", field.name()));
+            }
+
+            let prop = data::Prop {
+                label: label.clone(),
+                data_type: data_type.clone(),
+                value,
+            };
+            match field.metadata().get("entity") {
+                Some(x) if x.contains("-") => {
+                    relationships.entry(x.clone()).or_default().push(prop);
+                }
+                Some(x) => {
+                    // ids key messages, so they are kept as plain text whatever their type
+                    match (label == "sourceId", &prop.value) {
+                        (false, _) | (true, data::Value::Null) => {}
+                        (true, data::Value::String(id)) => {
+                            entity_ids.insert(x.clone(), id.clone());
+                        }
+                        (true, id) => {
+                            let id = serde_json::to_string(id).map_err(|e| e.to_string())?;
+                            entity_ids.insert(x.clone(), id);
+                        }
+                    }
+                    entities.entry(x.clone()).or_default().push(prop);
+                }
+                // refused in resolve_columns
+                None => {}
+            };
+        }
+
+        entities.into_iter().for_each(|(k, props)| {
+            let tmp = k.replace("!", ".");
+            let mut tmp_iter = tmp.split(".");
+            let type_ = tmp_iter.next().unwrap().to_string();
+            let sub_type = tmp_iter.next().unwrap().to_string();
+            let set_id = tmp_iter.next().map(|x| x.to_string());
+
+            // an entity without a sourceId cannot be keyed, so it is left out
+            let source_id = match entity_ids.get(&k) {
+                Some(x) => x.clone(),
+                None => return,
+            };
+
+            messages.push(data::Message::Entity(data::Entity {
+                fqn: k,
+                type_,
+                sub_type,
+                set_id,
+                source_id,
+                props,
+            }));
+        });
+
+        self.schema.relationships.iter().for_each(|x| {
+            let reference_split: Vec<&str> = x.reference.split("-").collect();
+            let from = reference_split.first().to_owned().unwrap();
+            let to = &reference_split.last().unwrap().to_owned();
+            let (from_id, to_id) = match (entity_ids.get(*from), entity_ids.get(*to)) {
+                (Some(x), Some(y)) => (x, y),
+                _ => return,
+            };
+
+            messages.push(data::Message::Relationship(data::Relationship {
+                reference: x.reference.clone(),
+                rel_type: x.label.clone(),
+                from_id: from_id.clone(),
+                to_id: to_id.clone(),
+                props: relationships.get(&x.reference).cloned().unwrap_or_default(),
+            }));
+        });
+
+        Ok(())
+    }
+}
+
+// reads one value of a column already cast to its mapping type
+fn value(column: &dyn Array, row: usize, data_type: &str) -> data::Value {
+    if column.is_null(row) {
+        return data::Value::Null;
+    }
+
+    match column.data_type() {
+        DataType::List(_) => {
+            let list = column.as_list::<i32>().value(row);
+            data::Value::List(
+                (0..list.len())
+                    .map(|i| value(list.as_ref(), i, data_type))
+                    .collect(),
+            )
+        }
+        DataType::Boolean => data::Value::Bool(column.as_boolean().value(row)),
+        DataType::Int64 => data::Value::Int(column.as_primitive::<Int64Type>().value(row)),
+        DataType::Float64 => data::Value::Float(column.as_primitive::<Float64Type>().value(row)),
+        DataType::Decimal128(_, _) => {
+            data::Value::Decimal(column.as_primitive::<Decimal128Type>().value_as_string(row))
+        }
+        DataType::Date64 => {
+            let millis = column.as_primitive::<Date64Type>().value(row);
+            DateTime::from_timestamp_millis(millis)
+                .map(|x| data::Value::Date(x.date_naive()))
+                .unwrap_or(data::Value::Null)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            let micros = column.as_primitive::<TimestampMicrosecondType>().value(row);
+            DateTime::from_timestamp_micros(micros)
+                .map(data::Value::Timestamp)
+                .unwrap_or(data::Value::Null)
+        }
+        DataType::Utf8 => {
+            let text = column.as_string::<i32>().value(row);
+            match data_type {
+                "Json" => serde_json::from_str(text)
+                    .map(data::Value::Json)
+                    .unwrap_or(data::Value::String(text.to_string())),
+                _ => data::Value::String(text.to_string()),
+            }
+        }
+        _ => data::Value::String(
+            ArrayFormatter::try_new(column, &Default::default())
+                .map(|x| x.value(row).to_string())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::StringArray;
+    use arrow::datatypes::Field;
+    use std::sync::Arc;
+
+    const MAPPING: &str = "
+fields:
+- label: sourceId
+  dataType: String
+  reference: PERSON.Person
+- label: age
+  dataType: Int
+  reference: PERSON.Person
+- label: addressId
+  labelOverride: address_id
+  dataType: String
+  reference: PERSON.Person
+";
+
+    fn mapping() -> map::Schema {
+        map::from_mapping(MAPPING.as_bytes())
+    }
+
+    fn columns(names: &[&str]) -> Schema {
+        Schema::new(
+            names
+                .iter()
+                .map(|x| Field::new(*x, DataType::Utf8, true))
+                .collect::<Vec<Field>>(),
+        )
+    }
+
+    fn resolve(names: &[&str]) -> Result<Vec<usize>, String> {
+        let mapping = mapping();
+        let result = Mapper::new(&mapping).resolve_columns(&columns(names));
+        result
+    }
+
+    #[test]
+    fn resolve_by_name_in_any_order() {
+        assert_eq!(
+            resolve(&["addressId", "age", "sourceId"]),
+            Ok(vec![2, 1, 0])
+        );
+        assert_eq!(
+            resolve(&["extra", "sourceId", "age", "addressId"]),
+            Ok(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn resolve_falls_back_to_label_override() {
+        assert_eq!(
+            resolve(&["sourceId", "age", "address_id"]),
+            Ok(vec![0, 1, 2])
+        );
+        // the label itself wins over the override
+        assert_eq!(
+            resolve(&["address_id", "sourceId", "age", "addressId"]),
+            Ok(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn resolve_ignores_case_when_nothing_matches_exactly() {
+        assert_eq!(
+            resolve(&["sourceid", "AGE", "addressid"]),
+            Ok(vec![0, 1, 2])
+        );
+        assert_eq!(
+            resolve(&["sourceid", "age", "ADDRESS_ID"]),
+            Ok(vec![0, 1, 2])
+        );
+        // an exact match is never ambiguous
+        assert_eq!(
+            resolve(&["sourceid", "sourceId", "age", "addressId"]),
+            Ok(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn resolve_refuses_ambiguous_columns() {
+        assert_eq!(
+            resolve(&["sourceid", "SOURCEID", "age", "addressId"]),
+            Err(
+                "mapped fields match more than one column: sourceId (sourceid, SOURCEID)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn resolve_reports_missing_fields() {
+        assert_eq!(
+            resolve(&["sourceId"]),
+            Err("mapped fields missing from the source: age, addressId".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_refuses_unreadable_columns() {
+        let mapping = mapping();
+        let unsupported = HashMap::from([("unsupported".to_string(), "interval".to_string())]);
+        let schema = Schema::new(vec![
+            Field::new("sourceId", DataType::Utf8, true),
+            Field::new("age", DataType::Utf8, true).with_metadata(unsupported.clone()),
+            Field::new("addressId", DataType::Utf8, true),
+            Field::new("span", DataType::Utf8, true).with_metadata(unsupported),
+        ]);
+        assert_eq!(
+            Mapper::new(&mapping).resolve_columns(&schema),
+            Err(
+                "mapped fields have a type the source cannot read: age (column age is interval)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn resolve_refuses_fields_without_a_reference() {
+        let mapping = map::from_mapping("fields:\n- label: id\n  dataType: String\n".as_bytes());
+        assert_eq!(
+            Mapper::new(&mapping).resolve_columns(&columns(&["id"])),
+            Err("mapped fields have no reference: id".to_string())
+        );
+    }
+
+    #[test]
+    fn map_skips_rows_that_cannot_be_read() {
+        let mapping = mapping();
+        let batch = RecordBatch::try_new(
+            Arc::new(columns(&["age", "sourceid", "address_id"])),
+            vec![
+                Arc::new(StringArray::from(vec!["7", "old"])),
+                Arc::new(StringArray::from(vec!["a", "b"])),
+                Arc::new(StringArray::from(vec!["x", "y"])),
+            ],
+        )
+        .unwrap();
+        let messages = Mapper::new(&mapping).map(&batch).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].key(), "PERSON.Person.a");
+    }
+}
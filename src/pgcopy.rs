@@ -0,0 +1,434 @@
+use arrow::array::{Array, ArrayRef, AsArray, RecordBatch};
+use arrow::datatypes::{
+    DataType, Date32Type, Date64Type, Decimal128Type, Field, Float32Type, Float64Type, Int16Type,
+    Int32Type, Int64Type, Int8Type, TimeUnit, TimestampMicrosecondType, TimestampMillisecondType,
+    TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use postgres::types::Type;
+
+// https://www.postgresql.org/docs/current/sql-copy.html#id-1.9.3.55.9.4
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+// postgres counts dates and timestamps from 2000-01-01
+const EPOCH_DAYS: i64 = 10_957;
+const EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+type FieldWriter<'a> = Box<dyn Fn(usize, &mut Vec<u8>) -> Result<(), String> + 'a>;
+
+// strings carry the mapping's dataType so uuid and json columns get their own types
+fn string_type(field: &Field) -> Type {
+    match field.metadata().get("dataType").map(|x| x.as_str()) {
+        Some("Uuid") => Type::UUID,
+        Some("Json") => Type::JSONB,
+        _ => Type::VARCHAR,
+    }
+}
+
+fn array_type(element: &Type) -> Result<Type, String> {
+    Ok(match *element {
+        Type::BOOL => Type::BOOL_ARRAY,
+        Type::INT2 => Type::INT2_ARRAY,
+        Type::INT4 => Type::INT4_ARRAY,
+        Type::INT8 => Type::INT8_ARRAY,
+        Type::FLOAT4 => Type::FLOAT4_ARRAY,
+        Type::FLOAT8 => Type::FLOAT8_ARRAY,
+        Type::NUMERIC => Type::NUMERIC_ARRAY,
+        Type::VARCHAR => Type::VARCHAR_ARRAY,
+        Type::BYTEA => Type::BYTEA_ARRAY,
+        Type::UUID => Type::UUID_ARRAY,
+        Type::JSONB => Type::JSONB_ARRAY,
+        Type::DATE => Type::DATE_ARRAY,
+        Type::TIMESTAMP => Type::TIMESTAMP_ARRAY,
+        Type::TIMESTAMPTZ => Type::TIMESTAMPTZ_ARRAY,
+        _ => return Err(format!("arrays of {} are not supported", element)),
+    })
+}
+
+// the postgres column type an arrow field is loaded into
+pub fn pg_type(field: &Field) -> Result<Type, String> {
+    Ok(match field.data_type() {
+        DataType::Boolean => Type::BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => Type::INT2,
+        DataType::Int32 | DataType::UInt16 => Type::INT4,
+        DataType::Int64 | DataType::UInt32 => Type::INT8,
+        DataType::UInt64 | DataType::Decimal128(_, _) => Type::NUMERIC,
+        DataType::Float32 => Type::FLOAT4,
+        DataType::Float64 => Type::FLOAT8,
+        DataType::Utf8 => string_type(field),
+        DataType::LargeUtf8 => Type::VARCHAR,
+        DataType::Binary | DataType::LargeBinary => Type::BYTEA,
+        DataType::FixedSizeBinary(16) => Type::UUID,
+        DataType::Date32 | DataType::Date64 => Type::DATE,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::List(inner) => array_type(&pg_type(inner)?)?,
+        x => return Err(format!("{} has unsupported type {}", field.name(), x)),
+    })
+}
+
+// the type as written in `create table`
+pub fn type_name(field: &Field) -> Result<String, String> {
+    Ok(match field.data_type() {
+        DataType::Decimal128(precision, scale) => format!("numeric({}, {})", precision, scale),
+        DataType::UInt64 => "numeric(20, 0)".to_string(),
+        DataType::List(inner) => format!("{}[]", type_name(inner)?),
+        _ => pg_type(field)?.name().to_string(),
+    })
+}
+
+// never fails, the Result lets every writer end with it
+fn write_field(buf: &mut Vec<u8>, value: &[u8]) -> Result<(), String> {
+    buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+// numeric is sent as base 10000 digit groups around the decimal point
+fn numeric(value: i128, scale: i8) -> Vec<u8> {
+    let (value, scale) = match scale {
+        x if x < 0 => (value * 10i128.pow(x.unsigned_abs() as u32), 0),
+        x => (value, x as usize),
+    };
+
+    let digits = format!("{:0>1$}", value.unsigned_abs(), scale + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale);
+    let int_pad = (4 - int.len() % 4) % 4;
+    let frac_pad = (4 - frac.len() % 4) % 4;
+    let padded = format!(
+        "{}{}{}{}",
+        "0".repeat(int_pad),
+        int,
+        frac,
+        "0".repeat(frac_pad)
+    );
+
+    let mut groups: Vec<i16> = padded
+        .as_bytes()
+        .chunks(4)
+        .map(|x| std::str::from_utf8(x).unwrap().parse().unwrap())
+        .collect();
+    let mut weight = ((int.len() + int_pad) / 4) as i16 - 1;
+    while groups.first() == Some(&0) {
+        groups.remove(0);
+        weight -= 1;
+    }
+    while groups.last() == Some(&0) {
+        groups.pop();
+    }
+    if groups.is_empty() {
+        weight = 0;
+    }
+
+    let sign: u16 = if value < 0 { 0x4000 } else { 0 };
+    let mut buf: Vec<u8> = vec![];
+    buf.extend_from_slice(&(groups.len() as i16).to_be_bytes());
+    buf.extend_from_slice(&weight.to_be_bytes());
+    buf.extend_from_slice(&sign.to_be_bytes());
+    buf.extend_from_slice(&(scale as i16).to_be_bytes());
+    groups
+        .iter()
+        .for_each(|x| buf.extend_from_slice(&x.to_be_bytes()));
+    buf
+}
+
+fn uuid(value: &str) -> Result<[u8; 16], String> {
+    let hex: String = value.chars().filter(|x| *x != '-').collect();
+    match u128::from_str_radix(&hex, 16) {
+        Ok(x) if hex.len() == 32 => Ok(x.to_be_bytes()),
+        _ => Err(format!("invalid uuid: {}", value)),
+    }
+}
+
+fn timestamp(value: i64, unit: &TimeUnit) -> [u8; 8] {
+    let micros = match unit {
+        TimeUnit::Second => value * 1_000_000,
+        TimeUnit::Millisecond => value * 1_000,
+        TimeUnit::Microsecond => value,
+        TimeUnit::Nanosecond => value.div_euclid(1_000),
+    };
+    (micros - EPOCH_MICROS).to_be_bytes()
+}
+
+// each writer encodes one value of a column in the binary format of the
+// column type `pg_type` picks for it
+fn field_writer<'a>(field: &Field, column: &'a ArrayRef) -> Result<FieldWriter<'a>, String> {
+    Ok(match column.data_type() {
+        DataType::Boolean => {
+            let array = column.as_boolean();
+            Box::new(move |row, buf| write_field(buf, &[array.value(row) as u8]))
+        }
+        DataType::Int8 => {
+            let array = column.as_primitive::<Int8Type>();
+            Box::new(move |row, buf| write_field(buf, &(array.value(row) as i16).to_be_bytes()))
+        }
+        DataType::Int16 => {
+            let array = column.as_primitive::<Int16Type>();
+            Box::new(move |row, buf| write_field(buf, &array.value(row).to_be_bytes()))
+        }
+        DataType::Int32 => {
+            let array = column.as_primitive::<Int32Type>();
+            Box::new(move |row, buf| write_field(buf, &array.value(row).to_be_bytes()))
+        }
+        DataType::Int64 => {
+            let array = column.as_primitive::<Int64Type>();
+            Box::new(move |row, buf| write_field(buf, &array.value(row).to_be_bytes()))
+        }
+        DataType::UInt8 => {
+            let array = column.as_primitive::<UInt8Type>();
+            Box::new(move |row, buf| write_field(buf, &(array.value(row) as i16).to_be_bytes()))
+        }
+        DataType::UInt16 => {
+            let array = column.as_primitive::<UInt16Type>();
+            Box::new(move |row, buf| write_field(buf, &(array.value(row) as i32).to_be_bytes()))
+        }
+        DataType::UInt32 => {
+            let array = column.as_primitive::<UInt32Type>();
+            Box::new(move |row, buf| write_field(buf, &(array.value(row) as i64).to_be_bytes()))
+        }
+        DataType::UInt64 => {
+            let array = column.as_primitive::<UInt64Type>();
+            Box::new(move |row, buf| write_field(buf, &numeric(array.value(row) as i128, 0)))
+        }
+        DataType::Float32 => {
+            let array = column.as_primitive::<Float32Type>();
+            Box::new(move |row, buf| write_field(buf, &array.value(row).to_be_bytes()))
+        }
+        DataType::Float64 => {
+            let array = column.as_primitive::<Float64Type>();
+            Box::new(move |row, buf| write_field(buf, &array.value(row).to_be_bytes()))
+        }
+        DataType::Decimal128(_, scale) => {
+            let array = column.as_primitive::<Decimal128Type>();
+            let scale = *scale;
+            Box::new(move |row, buf| write_field(buf, &numeric(array.value(row), scale)))
+        }
+        DataType::Utf8 => {
+            let array = column.as_string::<i32>();
+            match string_type(field) {
+                Type::UUID => Box::new(move |row, buf| write_field(buf, &uuid(array.value(row))?)),
+                Type::JSONB => Box::new(move |row, buf| {
+                    // jsonb is a version byte followed by the json text
+                    let value = array.value(row).as_bytes();
+                    buf.extend_from_slice(&(value.len() as i32 + 1).to_be_bytes());
+                    buf.push(1);
+                    buf.extend_from_slice(value);
+                    Ok(())
+                }),
+                _ => Box::new(move |row, buf| write_field(buf, array.value(row).as_bytes())),
+            }
+        }
+        DataType::LargeUtf8 => {
+            let array = column.as_string::<i64>();
+            Box::new(move |row, buf| write_field(buf, array.value(row).as_bytes()))
+        }
+        DataType::Binary => {
+            let array = column.as_binary::<i32>();
+            Box::new(move |row, buf| write_field(buf, array.value(row)))
+        }
+        DataType::LargeBinary => {
+            let array = column.as_binary::<i64>();
+            Box::new(move |row, buf| write_field(buf, array.value(row)))
+        }
+        DataType::FixedSizeBinary(16) => {
+            let array = column.as_fixed_size_binary();
+            Box::new(move |row, buf| write_field(buf, array.value(row)))
+        }
+        DataType::Date32 => {
+            let array = column.as_primitive::<Date32Type>();
+            Box::new(move |row, buf| {
+                let days = array.value(row) as i64 - EPOCH_DAYS;
+                write_field(buf, &(days as i32).to_be_bytes())
+            })
+        }
+        DataType::Date64 => {
+            let array = column.as_primitive::<Date64Type>();
+            Box::new(move |row, buf| {
+                let days = array.value(row).div_euclid(86_400_000) - EPOCH_DAYS;
+                write_field(buf, &(days as i32).to_be_bytes())
+            })
+        }
+        DataType::Timestamp(unit, _) => {
+            let values: Box<dyn Fn(usize) -> i64> = match unit {
+                TimeUnit::Second => {
+                    let array = column.as_primitive::<TimestampSecondType>();
+                    Box::new(move |row| array.value(row))
+                }
+                TimeUnit::Millisecond => {
+                    let array = column.as_primitive::<TimestampMillisecondType>();
+                    Box::new(move |row| array.value(row))
+                }
+                TimeUnit::Microsecond => {
+                    let array = column.as_primitive::<TimestampMicrosecondType>();
+                    Box::new(move |row| array.value(row))
+                }
+                TimeUnit::Nanosecond => {
+                    let array = column.as_primitive::<TimestampNanosecondType>();
+                    Box::new(move |row| array.value(row))
+                }
+            };
+            let unit = *unit;
+            Box::new(move |row, buf| write_field(buf, &timestamp(values(row), &unit)))
+        }
+        DataType::List(inner) => {
+            let array = column.as_list::<i32>();
+            let values = array.values();
+            let element = field_writer(inner, values)?;
+            let oid = pg_type(inner)?.oid();
+            Box::new(move |row, buf| {
+                let offsets = array.value_offsets();
+                let (start, end) = (offsets[row] as usize, offsets[row + 1] as usize);
+                let has_null = (start..end).any(|i| values.is_null(i));
+
+                // one dimension with a lower bound of 1, or no dimensions when empty
+                let mut payload: Vec<u8> = vec![];
+                payload.extend_from_slice(&((start < end) as i32).to_be_bytes());
+                payload.extend_from_slice(&(has_null as i32).to_be_bytes());
+                payload.extend_from_slice(&oid.to_be_bytes());
+                if start < end {
+                    payload.extend_from_slice(&((end - start) as i32).to_be_bytes());
+                    payload.extend_from_slice(&1i32.to_be_bytes());
+                }
+                for i in start..end {
+                    if values.is_null(i) {
+                        payload.extend_from_slice(&(-1i32).to_be_bytes());
+                    } else {
+                        element(i, &mut payload)?;
+                    }
+                }
+
+                write_field(buf, &payload)
+            })
+        }
+        x => return Err(format!("{} has unsupported type {}", field.name(), x)),
+    })
+}
+
+// encode a whole batch as the payload of `copy ... from stdin (format binary)`,
+// first_row numbers the batch's rows in errors
+pub fn encode(batch: &RecordBatch, first_row: usize) -> Result<Vec<u8>, String> {
+    let mut buf: Vec<u8> = Vec::with_capacity(batch.get_array_memory_size());
+    buf.extend_from_slice(SIGNATURE);
+    buf.extend_from_slice(&0i32.to_be_bytes()); // flags
+    buf.extend_from_slice(&0i32.to_be_bytes()); // header extension length
+
+    let schema = batch.schema();
+    let writers: Vec<FieldWriter> = schema
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(|(field, column)| field_writer(field, column))
+        .collect::<Result<_, _>>()?;
+    let num_columns = batch.num_columns() as i16;
+    for row in 0..batch.num_rows() {
+        buf.extend_from_slice(&num_columns.to_be_bytes());
+        for ((field, column), writer) in schema.fields().iter().zip(batch.columns()).zip(&writers) {
+            if column.is_null(row) {
+                buf.extend_from_slice(&(-1i32).to_be_bytes());
+            } else {
+                writer(row, &mut buf).map_err(|e| {
+                    format!(
+                        "row {}, column {}: {}",
+                        first_row + row + 1,
+                        field.name(),
+                        e
+                    )
+                })?;
+            }
+        }
+    }
+
+    buf.extend_from_slice(&(-1i16).to_be_bytes());
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::StringArray;
+    use arrow::datatypes::Schema;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    // ndigits, weight, sign and dscale headers followed by the base 10000 groups
+    fn expected(weight: i16, sign: u16, dscale: i16, groups: &[i16]) -> Vec<u8> {
+        let mut buf: Vec<u8> = vec![];
+        buf.extend_from_slice(&(groups.len() as i16).to_be_bytes());
+        buf.extend_from_slice(&weight.to_be_bytes());
+        buf.extend_from_slice(&sign.to_be_bytes());
+        buf.extend_from_slice(&dscale.to_be_bytes());
+        groups
+            .iter()
+            .for_each(|x| buf.extend_from_slice(&x.to_be_bytes()));
+        buf
+    }
+
+    #[test]
+    fn numeric_fraction() {
+        assert_eq!(numeric(5, 2), expected(-1, 0, 2, &[500]));
+    }
+
+    #[test]
+    fn numeric_negative() {
+        assert_eq!(numeric(-12345, 2), expected(0, 0x4000, 2, &[123, 4500]));
+    }
+
+    #[test]
+    fn numeric_zero() {
+        assert_eq!(numeric(0, 0), expected(0, 0, 0, &[]));
+        assert_eq!(numeric(0, 10), expected(0, 0, 10, &[]));
+    }
+
+    #[test]
+    fn numeric_negative_scale() {
+        assert_eq!(numeric(12, -3), expected(1, 0, 0, &[1, 2000]));
+    }
+
+    #[test]
+    fn numeric_default_scale() {
+        assert_eq!(
+            numeric(12_345_678_901, 10),
+            expected(0, 0, 10, &[1, 2345, 6789, 100])
+        );
+    }
+
+    #[test]
+    fn numeric_trailing_zero_groups() {
+        assert_eq!(numeric(100_000_000, 0), expected(2, 0, 0, &[1]));
+    }
+
+    #[test]
+    fn uuid_parses_with_or_without_dashes() {
+        let bytes = uuid("4257a0c4-1890-4ab5-bafd-152870e078dc").unwrap();
+        assert_eq!(bytes, uuid("4257a0c418904ab5bafd152870e078dc").unwrap());
+        assert_eq!(bytes[0], 0x42);
+        assert_eq!(bytes[15], 0xdc);
+        assert_eq!(uuid("nope").err().unwrap(), "invalid uuid: nope");
+        assert!(uuid("4257a0c4-1890-4ab5-bafd-152870e078zz").is_err());
+    }
+
+    #[test]
+    fn encode_names_the_bad_row_and_column() {
+        let metadata = HashMap::from([("dataType".to_string(), "Uuid".to_string())]);
+        let field = Field::new("id", DataType::Utf8, true).with_metadata(metadata);
+        let ids = StringArray::from(vec![
+            Some("4257a0c4-1890-4ab5-bafd-152870e078dc"),
+            None,
+            Some("x"),
+        ]);
+        let batch =
+            RecordBatch::try_new(Arc::new(Schema::new(vec![field])), vec![Arc::new(ids)]).unwrap();
+        assert_eq!(
+            encode(&batch, 10).err().unwrap(),
+            "row 13, column id: invalid uuid: x"
+        );
+    }
+
+    #[test]
+    fn unsupported_types_are_errors() {
+        let field = Field::new("span", DataType::Duration(TimeUnit::Second), true);
+        assert_eq!(
+            pg_type(&field).err().unwrap(),
+            "span has unsupported type Duration(Second)"
+        );
+    }
+}
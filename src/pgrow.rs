@@ -0,0 +1,420 @@
+use arrow::array::{
+    new_null_array, ArrayRef, BooleanArray, Date32Array, Float64Array, Int64Array, ListArray,
+    RecordBatch, RecordBatchOptions, StringArray, Time64MicrosecondArray,
+    TimestampMicrosecondArray,
+};
+use arrow::buffer::{NullBuffer, OffsetBuffer};
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat, Utc};
+use postgres::types::{FromSql, Kind, Type};
+use postgres::{Column, Row};
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::Arc;
+
+// a column value as postgres sent it, before it goes into an arrow array
+#[derive(Debug, Clone)]
+enum Cell {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Numeric(String),
+    Text(String),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    // timestamp without time zone, read as utc like the columns `load` writes
+    Timestamp(NaiveDateTime),
+    TimestampTz(DateTime<Utc>),
+    Uuid(String),
+    Json(Json),
+    Bytes(Vec<u8>),
+    Array(Vec<Cell>),
+}
+
+// numeric has no rust counterpart, so it is read as its exact decimal text
+struct Numeric(String);
+
+impl<'a> FromSql<'a> for Numeric {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let word = |i: usize| -> Result<u16, Box<dyn Error + Sync + Send>> {
+            raw.get(i * 2..i * 2 + 2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .ok_or("numeric value too short".into())
+        };
+        let ndigits = word(0)? as usize;
+        let weight = word(1)? as i16 as i64;
+        let sign = word(2)?;
+        let dscale = word(3)? as i64;
+        let digits = (0..ndigits)
+            .map(|i| word(4 + i))
+            .collect::<Result<Vec<u16>, _>>()?;
+
+        match sign {
+            0xc000 => return Ok(Numeric("NaN".to_string())),
+            0xd000 => return Ok(Numeric("Infinity".to_string())),
+            0xf000 => return Ok(Numeric("-Infinity".to_string())),
+            _ => {}
+        }
+
+        // digits are base 10000 groups, the first one worth 10000^weight
+        let group = |exponent: i64| -> u16 {
+            usize::try_from(weight - exponent)
+                .ok()
+                .and_then(|x| digits.get(x).copied())
+                .unwrap_or(0)
+        };
+
+        let mut text = String::new();
+        if sign == 0x4000 {
+            text.push('-');
+        }
+        if weight < 0 {
+            text.push('0');
+        } else {
+            write!(text, "{}", group(weight)).unwrap();
+            (0..weight)
+                .rev()
+                .for_each(|x| write!(text, "{:04}", group(x)).unwrap());
+        }
+        if dscale > 0 {
+            let mut fraction = String::new();
+            (1..=(dscale + 3) / 4).for_each(|x| write!(fraction, "{:04}", group(-x)).unwrap());
+            fraction.truncate(dscale as usize);
+            write!(text, ".{}", fraction).unwrap();
+        }
+
+        Ok(Numeric(text))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+}
+
+struct Uuid(String);
+
+impl<'a> FromSql<'a> for Uuid {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        if raw.len() != 16 {
+            return Err("uuid value is not 16 bytes".into());
+        }
+        let hex: String = raw.iter().map(|x| format!("{:02x}", x)).collect();
+        Ok(Uuid(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::UUID
+    }
+}
+
+// reads a column of T, or an array of T when the column is an array
+fn get<'a, T: FromSql<'a>>(
+    row: &'a Row,
+    i: usize,
+    array: bool,
+    cell: fn(T) -> Cell,
+) -> Result<Cell, String> {
+    let value = if array {
+        row.try_get::<usize, Option<Vec<Option<T>>>>(i).map(|x| {
+            x.map(|xs| Cell::Array(xs.into_iter().map(|x| x.map_or(Cell::Null, cell)).collect()))
+        })
+    } else {
+        row.try_get::<usize, Option<T>>(i).map(|x| x.map(cell))
+    };
+    value
+        .map(|x| x.unwrap_or(Cell::Null))
+        .map_err(|e| match e.source() {
+            Some(x) => x.to_string(),
+            None => e.to_string(),
+        })
+}
+
+fn decode(row: &Row, i: usize) -> Result<Cell, String> {
+    let column = &row.columns()[i];
+    let (array, ty) = match column.type_().kind() {
+        Kind::Array(x) => (true, x),
+        _ => (false, column.type_()),
+    };
+
+    match ty.name() {
+        "bool" => get(row, i, array, Cell::Bool),
+        "int2" => get(row, i, array, |x: i16| Cell::Int(x as i64)),
+        "int4" => get(row, i, array, |x: i32| Cell::Int(x as i64)),
+        "int8" => get(row, i, array, Cell::Int),
+        "oid" => get(row, i, array, |x: u32| Cell::Int(x as i64)),
+        "float4" => get(row, i, array, |x: f32| Cell::Float(x as f64)),
+        "float8" => get(row, i, array, Cell::Float),
+        "numeric" => get(row, i, array, |x: Numeric| Cell::Numeric(x.0)),
+        "date" => get(row, i, array, Cell::Date),
+        "time" => get(row, i, array, Cell::Time),
+        "timestamp" => get(row, i, array, Cell::Timestamp),
+        "timestamptz" => get(row, i, array, Cell::TimestampTz),
+        "uuid" => get(row, i, array, |x: Uuid| Cell::Uuid(x.0)),
+        "json" | "jsonb" => get(row, i, array, Cell::Json),
+        "bytea" => get(row, i, array, Cell::Bytes),
+        // text, varchar, bpchar, name and text-like extension types such as citext
+        _ if <String as FromSql>::accepts(ty) => get(row, i, array, Cell::Text),
+        x => Err(format!(
+            "unsupported column type {} for {}",
+            x,
+            column.name()
+        )),
+    }
+}
+
+// whether decode can read a column, on its own or as an array
+fn supported(ty: &Type) -> bool {
+    let ty = match ty.kind() {
+        Kind::Array(x) => x,
+        _ => ty,
+    };
+    matches!(
+        ty.name(),
+        "bool"
+            | "int2"
+            | "int4"
+            | "int8"
+            | "oid"
+            | "float4"
+            | "float8"
+            | "numeric"
+            | "date"
+            | "time"
+            | "timestamp"
+            | "timestamptz"
+            | "uuid"
+            | "json"
+            | "jsonb"
+            | "bytea"
+    ) || <String as FromSql>::accepts(ty)
+}
+
+// the text form of values arrow has no better type for
+fn text(cell: &Cell) -> Option<String> {
+    match cell {
+        Cell::Null | Cell::Array(_) => None,
+        Cell::Bool(x) => Some(x.to_string()),
+        Cell::Int(x) => Some(x.to_string()),
+        Cell::Float(x) => Some(x.to_string()),
+        Cell::Numeric(x) | Cell::Text(x) | Cell::Uuid(x) => Some(x.clone()),
+        Cell::Date(x) => Some(x.format("%Y-%m-%d").to_string()),
+        Cell::Time(x) => Some(x.format("%H:%M:%S%.f").to_string()),
+        Cell::Timestamp(x) => Some(x.format("%Y-%m-%dT%H:%M:%S%.f").to_string()),
+        Cell::TimestampTz(x) => Some(x.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        Cell::Json(x) => Some(x.to_string()),
+        Cell::Bytes(x) => Some(BASE64_STANDARD.encode(x)),
+    }
+}
+
+// numeric, uuid, json and bytea travel as text and are cast by whoever reads them
+fn arrow_type(ty: &Type) -> DataType {
+    match ty.kind() {
+        Kind::Array(x) => DataType::new_list(arrow_type(x), true),
+        _ => match ty.name() {
+            "bool" => DataType::Boolean,
+            "int2" | "int4" | "int8" | "oid" => DataType::Int64,
+            "float4" | "float8" => DataType::Float64,
+            "date" => DataType::Date32,
+            "time" => DataType::Time64(TimeUnit::Microsecond),
+            "timestamp" => DataType::Timestamp(TimeUnit::Microsecond, None),
+            "timestamptz" => DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into())),
+            _ => DataType::Utf8,
+        },
+    }
+}
+
+fn to_array(cells: &[Cell], ty: &Type) -> ArrayRef {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    match (ty.kind(), arrow_type(ty)) {
+        (Kind::Array(member), _) => {
+            let mut offsets: Vec<i32> = vec![0];
+            let mut valid: Vec<bool> = vec![];
+            let mut values: Vec<Cell> = vec![];
+            for cell in cells {
+                valid.push(matches!(cell, Cell::Array(_)));
+                if let Cell::Array(xs) = cell {
+                    values.extend(xs.iter().cloned());
+                }
+                offsets.push(values.len() as i32);
+            }
+            Arc::new(ListArray::new(
+                Arc::new(Field::new_list_field(arrow_type(member), true)),
+                OffsetBuffer::new(offsets.into()),
+                to_array(&values, member),
+                Some(NullBuffer::from(valid)),
+            ))
+        }
+        (_, DataType::Boolean) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Bool(x) => Some(*x),
+                    _ => None,
+                })
+                .collect::<BooleanArray>(),
+        ),
+        (_, DataType::Int64) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Int(x) => Some(*x),
+                    _ => None,
+                })
+                .collect::<Int64Array>(),
+        ),
+        (_, DataType::Float64) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Float(x) => Some(*x),
+                    _ => None,
+                })
+                .collect::<Float64Array>(),
+        ),
+        (_, DataType::Date32) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Date(x) => Some((*x - epoch).num_days() as i32),
+                    _ => None,
+                })
+                .collect::<Date32Array>(),
+        ),
+        (_, DataType::Time64(_)) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Time(x) => Some((*x - NaiveTime::MIN).num_microseconds().unwrap_or(0)),
+                    _ => None,
+                })
+                .collect::<Time64MicrosecondArray>(),
+        ),
+        (_, DataType::Timestamp(_, tz)) => Arc::new(
+            cells
+                .iter()
+                .map(|x| match x {
+                    Cell::Timestamp(x) => Some(x.and_utc().timestamp_micros()),
+                    Cell::TimestampTz(x) => Some(x.timestamp_micros()),
+                    _ => None,
+                })
+                .collect::<TimestampMicrosecondArray>()
+                .with_timezone_opt(tz),
+        ),
+        _ => Arc::new(cells.iter().map(text).collect::<StringArray>()),
+    }
+}
+
+// one fetch of rows as a batch named after the result columns. a column of a type
+// decode cannot read comes through as nulls marked `unsupported`, so it only fails
+// the batch if the mapping uses it. a single value that cannot be decoded, like a
+// date of infinity, is reported with its row and read as null rather than failing
+// the whole fetch, first_row is where the fetch starts in the result
+pub fn to_batch(rows: &[Row], columns: &[Column], first_row: usize) -> Result<RecordBatch, String> {
+    let mut arrays: Vec<ArrayRef> = vec![];
+    for (i, column) in columns.iter().enumerate() {
+        if !supported(column.type_()) {
+            arrays.push(new_null_array(&DataType::Utf8, rows.len()));
+            continue;
+        }
+        let cells: Vec<Cell> = rows
+            .iter()
+            .enumerate()
+            .map(|(n, row)| {
+                decode(row, i).unwrap_or_else(|e| {
+                    eprintln!(
+                        "row {}, column {}: {}, read as null",
+                        first_row + n + 1,
+                        column.name(),
+                        e
+                    );
+                    Cell::Null
+                })
+            })
+            .collect();
+        arrays.push(to_array(&cells, column.type_()));
+    }
+
+    let fields: Vec<Field> = columns
+        .iter()
+        .map(|x| match supported(x.type_()) {
+            true => Field::new(x.name(), arrow_type(x.type_()), true),
+            false => Field::new(x.name(), DataType::Utf8, true).with_metadata(HashMap::from([(
+                "unsupported".to_string(),
+                x.type_().name().to_string(),
+            )])),
+        })
+        .collect();
+    RecordBatch::try_new_with_options(
+        Arc::new(Schema::new(fields)),
+        arrays,
+        &RecordBatchOptions::new().with_row_count(Some(rows.len())),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ndigits, weight, sign and dscale headers followed by the base 10000 groups
+    fn numeric(weight: i16, sign: u16, dscale: i16, groups: &[u16]) -> String {
+        let mut raw: Vec<u8> = vec![];
+        raw.extend_from_slice(&(groups.len() as i16).to_be_bytes());
+        raw.extend_from_slice(&weight.to_be_bytes());
+        raw.extend_from_slice(&sign.to_be_bytes());
+        raw.extend_from_slice(&dscale.to_be_bytes());
+        groups
+            .iter()
+            .for_each(|x| raw.extend_from_slice(&x.to_be_bytes()));
+        Numeric::from_sql(&Type::NUMERIC, &raw).unwrap().0
+    }
+
+    #[test]
+    fn numeric_negative() {
+        assert_eq!(numeric(0, 0x4000, 2, &[123, 4500]), "-123.45");
+        assert_eq!(numeric(0, 0x4000, 0, &[7]), "-7");
+    }
+
+    #[test]
+    fn numeric_fraction() {
+        assert_eq!(numeric(-1, 0, 2, &[500]), "0.05");
+        assert_eq!(numeric(-2, 0, 6, &[1200]), "0.000012");
+        assert_eq!(numeric(0, 0, 10, &[1, 2345, 6789, 100]), "1.2345678901");
+    }
+
+    #[test]
+    fn numeric_zero() {
+        assert_eq!(numeric(0, 0, 0, &[]), "0");
+        assert_eq!(numeric(0, 0, 2, &[]), "0.00");
+    }
+
+    #[test]
+    fn numeric_large_weight() {
+        assert_eq!(numeric(3, 0, 0, &[1]), "1000000000000");
+        assert_eq!(numeric(2, 0, 1, &[12, 0, 5, 5000]), "1200000005.5");
+    }
+
+    #[test]
+    fn numeric_special_values() {
+        assert_eq!(numeric(0, 0xc000, 0, &[]), "NaN");
+        assert_eq!(numeric(0, 0xd000, 0, &[]), "Infinity");
+        assert_eq!(numeric(0, 0xf000, 0, &[]), "-Infinity");
+    }
+
+    #[test]
+    fn numeric_rejects_short_values() {
+        assert!(Numeric::from_sql(&Type::NUMERIC, &[0, 1, 0]).is_err());
+        assert!(Numeric::from_sql(&Type::NUMERIC, &[0, 2, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    }
+}
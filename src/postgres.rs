@@ -1,289 +1,979 @@
 use crate::data;
-use crate::map;
+use crate::pgconn;
+use crate::pgcopy;
+use crate::pgrow;
 use arrow::array::RecordBatch;
-use arrow::array::{Date64Array, Int64Array, PrimitiveArray, StringArray};
 use arrow::datatypes;
-use clap::ArgMatches;
-use postgres::{Client, Error, NoTls, Row};
-use serde_json::json;
+use postgres::{Client, Error, Portal, Statement, Transaction};
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::io::Write as _;
+
+// database.schema.table, where any part may be "double quoted" to keep dots or case.
+// unquoted schema and table names fold to lowercase as they do in sql, the database
+// is a connection parameter and is used as given
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableName {
+    pub database: String,
+    pub schema: String,
+    pub table: String,
+}
+
+impl TableName {
+    pub fn parse(fqn: &str) -> Result<TableName, String> {
+        // each part as given and as sql folds it
+        let mut parts: Vec<(String, String)> = vec![];
+        let mut part = (String::new(), String::new());
+        let mut quoted = false;
+        let mut chars = fqn.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    part.0.push('"');
+                    part.1.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                '.' if !quoted => parts.push(std::mem::take(&mut part)),
+                _ => {
+                    part.0.push(c);
+                    part.1.push(if quoted { c } else { c.to_ascii_lowercase() });
+                }
+            }
+        }
+        parts.push(part);
+
+        if quoted {
+            return Err(format!("unterminated quote in {}", fqn));
+        }
+
+        match parts.as_slice() {
+            [(database, _), (_, schema), (_, table)]
+                if !database.is_empty() && !schema.is_empty() && !table.is_empty() =>
+            {
+                Ok(TableName {
+                    database: database.clone(),
+                    schema: schema.clone(),
+                    table: table.clone(),
+                })
+            }
+            _ => Err(format!("expected database.schema.table, got {}", fqn)),
+        }
+    }
+
+    pub fn qualified(&self) -> String {
+        format!("{}.{}", quote(&self.schema), quote(&self.table))
+    }
+}
+
+pub fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+// server errors carry the message and detail, the outer error only says "db error"
+fn describe(e: Error) -> String {
+    match e.as_db_error() {
+        Some(x) => x.to_string(),
+        None => e.to_string(),
+    }
+}
+
+fn quote_all(identifiers: &[String]) -> String {
+    identifiers
+        .iter()
+        .map(|x| quote(x))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
 
 pub struct Provider {
-    pub fqn_table: String,
+    pub table: TableName,
     pub client: Client,
+    pub mode: data::LoadMode,
 }
 
 impl data::Database for Provider {
-    fn load(&mut self, batch: RecordBatch) {
+    fn prepare(
+        &mut self,
+        schema: datatypes::SchemaRef,
+        options: &data::LoadOptions,
+    ) -> Result<(), String> {
+        // ddl is transactional in postgres, so a failed load never drops the old table
+        self.client
+            .batch_execute("begin")
+            .expect("failed to start transaction");
+
+        // a replaced table is created from scratch, anything else evolves in place
+        let changes = match options.mode {
+            data::LoadMode::Replace => vec![],
+            _ => {
+                let columns = self.columns()?;
+                let primary_key = self.primary_key()?;
+                plan_changes(&self.table, &schema, &columns, primary_key, &options.key)?
+            }
+        };
+
+        let refused: Vec<&str> = changes
+            .iter()
+            .filter(|x| !x.safe && !options.allow_unsafe)
+            .map(|x| x.sql.as_str())
+            .collect();
+        if !refused.is_empty() && !options.dry_run {
+            return Err(format!(
+                "unsafe schema changes, pass --allow-unsafe-changes to apply:\n{}",
+                refused.join("\n")
+            ));
+        }
+
+        // a dry run still shows the whole plan, with refused changes marked in it
+        if !refused.is_empty() {
+            eprintln!(
+                "{} unsafe schema changes, marked below, need --allow-unsafe-changes",
+                refused.len()
+            );
+        }
+        let alters: Vec<String> = changes
+            .into_iter()
+            .map(|x| match !x.safe && !options.allow_unsafe {
+                true => format!("{} -- unsafe", x.sql),
+                false => x.sql,
+            })
+            .collect();
+        for sql in schema_to_ddl(&self.table, schema, options, &alters)? {
+            if options.dry_run {
+                println!("{}", sql.trim_end());
+            } else {
+                self.client.query(&sql, &[]).map_err(describe)?;
+            }
+        }
+
+        self.mode = options.mode.clone();
+        Ok(())
+    }
+
+    // constraint violations surface as errors so the load can roll back
+    fn load(&mut self, batch: RecordBatch, first_row: usize) -> Result<usize, String> {
+        self.copy(batch, first_row)
+    }
+
+    fn commit(&mut self) {
+        self.client
+            .batch_execute("commit")
+            .expect("failed to commit load");
+    }
+
+    fn rollback(&mut self) {
+        self.client
+            .batch_execute("rollback")
+            .expect("failed to roll back load");
+    }
+
+    fn read(
+        &mut self,
+        sql: &str,
+        fetch_size: i32,
+    ) -> Box<dyn Iterator<Item = Result<RecordBatch, String>> + '_> {
+        // a portal only lives as long as the transaction it was bound in
+        let mut transaction = match self.client.transaction() {
+            Ok(x) => x,
+            Err(e) => return Box::new(std::iter::once(Err(describe(e)))),
+        };
+
+        // preparing first tells us the result columns before any row is fetched
+        let prepared = transaction.prepare(sql).and_then(|statement| {
+            let portal = transaction.bind(&statement, &[])?;
+            Ok((statement, portal))
+        });
+        match prepared {
+            Ok((statement, portal)) => Box::new(Batches {
+                transaction,
+                portal,
+                statement,
+                fetch_size,
+                read: 0,
+                done: false,
+            }),
+            Err(e) => Box::new(std::iter::once(Err(describe(e)))),
+        }
+    }
+}
+
+// fetches a query a chunk of rows at a time instead of holding the result set
+struct Batches<'a> {
+    transaction: Transaction<'a>,
+    portal: Portal,
+    statement: Statement,
+    fetch_size: i32,
+    // rows fetched so far
+    read: usize,
+    done: bool,
+}
+
+impl Iterator for Batches<'_> {
+    type Item = Result<RecordBatch, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let rows = match self.transaction.query_portal(&self.portal, self.fetch_size) {
+            Ok(x) => x,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(describe(e)));
+            }
+        };
+        // a short fetch means the portal is exhausted, which saves asking again
+        self.done = rows.len() < self.fetch_size as usize;
+        if rows.is_empty() {
+            return None;
+        }
+
+        let batch = pgrow::to_batch(&rows, self.statement.columns(), self.read);
+        self.read += rows.len();
+        Some(batch)
+    }
+}
+
+impl Provider {
+    fn copy(&mut self, batch: RecordBatch, first_row: usize) -> Result<usize, String> {
+        if batch.num_rows() == 0 {
+            return Ok(0);
+        }
+
         let cols: Vec<String> = batch
             .schema()
             .fields()
-            .clone()
             .iter()
             .map(|field| field.name().clone())
             .collect();
 
-        let mut dml = String::new();
-        writeln!(dml, "insert into {} ({})", self.fqn_table, cols.join(", "))
-            .expect("failed to write sql insert");
-
-        writeln!(dml, "values").expect("failed to write values");
-
-        for row in 0..batch.num_rows() {
-            let sep = if row == 0 { "" } else { ", " };
-            write!(dml, "\t{}(", sep).expect("failed to write values");
-            for col in 0..batch.num_columns() {
-                let sep = if col == 0 { "" } else { ", " };
-                write!(
-                    dml,
-                    "{}'{}'",
-                    sep,
-                    match batch.column(col).data_type().clone() {
-                        datatypes::DataType::Int64 => batch
-                            .column(col)
-                            .as_any()
-                            .downcast_ref::<Int64Array>()
-                            .expect("failed to downcast")
-                            .value(row)
-                            .to_string(),
-                        datatypes::DataType::Float64 => batch
-                            .column(col)
-                            .as_any()
-                            .downcast_ref::<PrimitiveArray<datatypes::Float64Type>>()
-                            .expect("failed to downcast")
-                            .value(row)
-                            .to_string(),
-                        datatypes::DataType::Date64 => batch
-                            .column(col)
-                            .as_any()
-                            .downcast_ref::<Date64Array>()
-                            .expect("failed to downcast")
-                            .value(row)
-                            .to_string(),
-                        datatypes::DataType::Utf8 => batch
-                            .column(col)
-                            .as_any()
-                            .downcast_ref::<StringArray>()
-                            .expect("failed to downcast")
-                            .value(row)
-                            .to_string()
-                            .replace("'", "''"),
-                        _ => unimplemented!(),
-                    }
-                )
-                .expect("failed to write values");
+        // copy cannot resolve conflicts, so upserts go through a staging table
+        let target = match &self.mode {
+            data::LoadMode::Upsert(_) => {
+                let staging = "pg_temp.em_staging";
+                self.client
+                    .batch_execute(&format!(
+                        "create temp table if not exists em_staging (like {}); truncate {};",
+                        self.table.qualified(),
+                        staging
+                    ))
+                    .map_err(describe)?;
+                staging.to_string()
             }
-            writeln!(dml, ")").expect("failed to write values");
+            _ => self.table.qualified(),
+        };
+
+        let copy = format!(
+            "copy {} ({}) from stdin (format binary)",
+            target,
+            quote_all(&cols)
+        );
+        // encoded before copy starts, so a bad value leaves nothing half written
+        let rows = pgcopy::encode(&batch, first_row)?;
+        let mut writer = self.client.copy_in(&copy).map_err(describe)?;
+        writer
+            .write_all(&rows)
+            .map_err(|e| format!("failed to write rows: {}", e))?;
+
+        let copied = writer.finish().map_err(describe)? as usize;
+
+        match &self.mode {
+            data::LoadMode::Upsert(key) => {
+                let updates: Vec<String> = cols
+                    .iter()
+                    .filter(|x| !key.contains(x))
+                    .map(|x| format!("{} = excluded.{}", quote(x), quote(x)))
+                    .collect();
+                let action = if updates.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    format!("update set {}", updates.join(", "))
+                };
+                // a key may only be upserted once per statement, the last staged row wins
+                let upsert = format!(
+                    "insert into {} ({}) select distinct on ({}) {} from {} \
+                     order by {}, ctid desc on conflict ({}) do {}",
+                    self.table.qualified(),
+                    quote_all(&cols),
+                    quote_all(key),
+                    quote_all(&cols),
+                    target,
+                    quote_all(key),
+                    quote_all(key),
+                    action
+                );
+                Ok(self.client.execute(&upsert, &[]).map_err(describe)? as usize)
+            }
+            _ => Ok(copied),
         }
+    }
 
-        let mut sql = schema_to_ddl(&self.fqn_table, batch.schema());
-        sql.push(dml);
-        sql.iter().for_each(|sql| {
-            self.client
-                .query(sql, &[])
-                .expect("failed to execute query");
-        });
+    // every column already in the table, empty when it does not exist yet
+    fn columns(&mut self) -> Result<Vec<Column>, String> {
+        let rows = self
+            .client
+            .query(
+                "select column_name::text, udt_name::text, numeric_precision::int4, \
+                 numeric_scale::int4 from information_schema.columns \
+                 where table_schema = $1 and table_name = $2 order by ordinal_position",
+                &[&self.table.schema, &self.table.table],
+            )
+            .map_err(describe)?;
+        Ok(rows
+            .iter()
+            .map(|x| {
+                let udt: String = x.get(1);
+                // integer columns report a binary precision, only numeric's is in digits
+                let precision: Option<i32> = x.get(2);
+                let numeric = match udt.as_str() {
+                    "numeric" => {
+                        precision.map(|p| (p, x.get::<usize, Option<i32>>(3).unwrap_or(0)))
+                    }
+                    _ => None,
+                };
+                Column {
+                    name: x.get(0),
+                    udt,
+                    numeric,
+                }
+            })
+            .collect())
     }
 
-    fn query(&mut self, sql: &str) -> Result<Vec<Row>, Error> {
-        self.client.query(&sql.to_string(), &[])
+    // name and sorted columns of the table's primary key, if it has one
+    fn primary_key(&mut self) -> Result<Option<(String, Vec<String>)>, String> {
+        let rows = self
+            .client
+            .query(
+                "select c.conname::text, a.attname::text from pg_constraint c \
+                 join pg_attribute a on a.attrelid = c.conrelid and a.attnum = any(c.conkey) \
+                 where c.conrelid = to_regclass($1) and c.contype = 'p' order by a.attname",
+                &[&self.table.qualified()],
+            )
+            .map_err(describe)?;
+        Ok(rows.first().map(|x| {
+            (
+                x.get(0),
+                rows.iter().map(|row| row.get(1)).collect::<Vec<String>>(),
+            )
+        }))
     }
+}
 
-    fn to_messages(&mut self, schema: map::Schema) -> Vec<data::Message> {
-        let res = self.query(schema.get_sql_source());
-        let mut messages: Vec<data::Message> = vec![];
-        match res {
-            Err(msg) => eprintln!("failed to get source: {}", msg),
-            Ok(rows) => {
-                rows.iter().for_each(|row| {
-                    row_into_messages(row, &schema, &mut messages);
-                });
-            }
+// a column already in the table, with the precision and scale of a constrained numeric
+struct Column {
+    name: String,
+    udt: String,
+    numeric: Option<(i32, i32)>,
+}
+
+struct Change {
+    sql: String,
+    // safe changes keep every row and column already in the table
+    safe: bool,
+}
+
+// precision and scale a field is loaded with, none when it is not a numeric
+fn numeric_of(field: &datatypes::Field) -> Option<(i32, i32)> {
+    match field.data_type() {
+        datatypes::DataType::Decimal128(precision, scale) => {
+            Some((*precision as i32, (*scale).max(0) as i32))
         }
+        datatypes::DataType::UInt64 => Some((20, 0)),
+        _ => None,
+    }
+}
 
-        messages
+// widening only ever gains range, so existing values convert without loss
+fn is_widening(from: &Column, to: &str, numeric: Option<(i32, i32)>) -> bool {
+    // the integer digits a numeric must keep to hold every value of the column
+    let digits = match (from.udt.as_str(), from.numeric) {
+        ("int2", _) => Some(5),
+        ("int4", _) => Some(10),
+        ("int8", _) => Some(19),
+        ("numeric", Some((precision, scale))) => Some(precision - scale),
+        _ => None,
+    };
+    match (from.udt.as_str(), to, numeric) {
+        ("int2", "int4" | "int8", _) | ("int4", "int8", _) | ("float4", "float8", _) => true,
+        // older versions stored dates as epoch milliseconds at midnight, see using
+        ("int8", "date", _) => true,
+        // unconstrained numeric holds anything
+        ("int2" | "int4" | "int8" | "numeric", "numeric", None) => true,
+        ("int2" | "int4" | "int8", "numeric", Some((precision, scale))) => {
+            digits.is_some_and(|x| precision - scale >= x)
+        }
+        ("numeric", "numeric", Some((precision, scale))) => match from.numeric {
+            Some((_, from_scale)) => {
+                scale >= from_scale && digits.is_some_and(|x| precision - scale >= x)
+            }
+            None => false,
+        },
+        _ => false,
     }
 }
 
-type Label = String;
-type Value = String;
-type DataType = String;
-type MessageField = (Label, Value, DataType);
+// how existing values are converted when a column changes type
+fn using(from: &Column, name: &str, type_name: &str) -> String {
+    match (from.udt.as_str(), type_name) {
+        // tables from before dates had their own type kept them as epoch milliseconds
+        ("int8", "date") => format!(
+            "(timestamp '1970-01-01' + {} * interval '1 millisecond')::date",
+            name
+        ),
+        _ => format!("{}::{}", name, type_name),
+    }
+}
 
-fn row_into_messages(row: &Row, schema: &map::Schema, messages: &mut Vec<data::Message>) {
-    let mut entities: HashMap<String, Vec<MessageField>> = HashMap::new();
-    let mut entity_ids: HashMap<String, String> = HashMap::new();
-    let mut relationships: HashMap<String, Vec<MessageField>> = HashMap::new();
+fn plan_changes(
+    table: &TableName,
+    schema: &datatypes::Schema,
+    columns: &[Column],
+    primary_key: Option<(String, Vec<String>)>,
+    key: &[String],
+) -> Result<Vec<Change>, String> {
+    // nothing to evolve, the table is created from the mapping
+    if columns.is_empty() {
+        return Ok(vec![]);
+    }
 
-    schema
-        .fields
-        .fields()
-        .iter()
-        .enumerate()
-        .for_each(|(i, field)| {
-            let col_type = row.columns().get(i).map(|c| c.type_().name()).unwrap();
-            let value = match col_type {
-                "varchar" => row.get::<usize, String>(i),
-                "int4" => row.get::<usize, i32>(i).to_string(),
-                "int8" => row.get::<usize, i64>(i).to_string(),
-                _ => unimplemented!(),
-            };
-
-            let label = field.metadata().get("label").unwrap();
-            let data_type = field.metadata().get("dataType").unwrap();
-            match field.metadata().get("entity") {
-                Some(x) if x.contains("-") => {
-                    relationships
-                        .entry(x.clone())
-                        .and_modify(|x| x.push((label.clone(), value.clone(), data_type.clone())))
-                        .or_insert(vec![(label.clone(), value, data_type.clone())]);
-                }
-                Some(x) => {
-                    if label == "sourceId" {
-                        entity_ids.insert(x.clone(), value.clone());
+    let fqn_name = table.qualified();
+    let mut changes: Vec<Change> = vec![];
+    // existing column names by the field they were matched to
+    let mut renamed: HashMap<&str, &str> = HashMap::new();
+    let mut matched: Vec<&str> = vec![];
+    for field in schema.fields() {
+        let name = quote(field.name());
+        let type_name = pgcopy::type_name(field)?;
+        let pg_type = pgcopy::pg_type(field)?;
+        let numeric = numeric_of(field);
+
+        // tables from before columns were quoted hold them folded to lowercase, so
+        // those are renamed rather than dropped and added again
+        let column = match columns.iter().find(|x| &x.name == field.name()) {
+            Some(x) => Some(x),
+            None => {
+                let folded: Vec<&Column> = columns
+                    .iter()
+                    .filter(|x| x.name.eq_ignore_ascii_case(field.name()))
+                    .filter(|x| schema.field_with_name(&x.name).is_err())
+                    .collect();
+                match folded.as_slice() {
+                    [] => None,
+                    [x] => {
+                        changes.push(Change {
+                            sql: format!(
+                                "alter table {} rename column {} to {};",
+                                fqn_name,
+                                quote(&x.name),
+                                name
+                            ),
+                            safe: true,
+                        });
+                        renamed.insert(&x.name, field.name());
+                        Some(*x)
+                    }
+                    xs => {
+                        return Err(format!(
+                            "{} matches more than one column of {}: {}",
+                            field.name(),
+                            fqn_name,
+                            xs.iter()
+                                .map(|x| x.name.as_str())
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        ))
                     }
-                    entities
-                        .entry(x.clone())
-                        .and_modify(|x| x.push((label.clone(), value.clone(), data_type.clone())))
-                        .or_insert(vec![(label.clone(), value, data_type.clone())]);
-                }
-                None => {
-                    unimplemented!()
                 }
-            };
-        });
-
-    entities.iter().for_each(|(k, v)| {
-        let tmp = k.replace("!", ".");
-        let mut tmp_iter = tmp.split(".");
-        let type_ = tmp_iter.next().unwrap();
-        let sub_type = tmp_iter.next().unwrap();
-        let set_id = tmp_iter.next().unwrap_or("");
-        let id = entity_ids.get(k).unwrap();
-        let key = format!("{}.{}.{}", type_, sub_type, id);
+            }
+        };
+        if let Some(x) = column {
+            matched.push(&x.name);
+        }
 
-        let props: serde_json::Value = v
-            .iter()
-            .map(|prop| {
-                json!({
-                    "label": prop.0,
-                    "value": prop.1,
-                    "dataType": prop.2,
+        match column {
+            // a required column cannot be filled in for rows already in the table
+            None => changes.push(Change {
+                sql: format!(
+                    "alter table {} add column {} {}{};",
+                    fqn_name,
+                    name,
+                    type_name,
+                    if field.is_nullable() { "" } else { " not null" }
+                ),
+                safe: field.is_nullable(),
+            }),
+            // a numeric whose precision or scale differs would round what is loaded into it
+            Some(x) if x.udt != pg_type.name() || (x.numeric.is_some() && x.numeric != numeric) => {
+                changes.push(Change {
+                    sql: format!(
+                        "alter table {} alter column {} type {} using {};",
+                        fqn_name,
+                        name,
+                        type_name,
+                        using(x, &name, &type_name)
+                    ),
+                    safe: is_widening(x, pg_type.name(), numeric),
                 })
-            })
-            .collect();
+            }
+            Some(_) => {}
+        }
+    }
 
-        messages.push(data::Message {
-            key,
-            value: json!({
-                "fqn": k,
-                "type": type_,
-                "subType": sub_type,
-                "setId": set_id,
-                "sourceId": id,
-                "props": props,
-            })
-            .to_string(),
-        });
-    });
+    for column in columns.iter().map(|x| &x.name) {
+        if !matched.contains(&column.as_str()) {
+            changes.push(Change {
+                sql: format!("alter table {} drop column {};", fqn_name, quote(column)),
+                safe: false,
+            });
+        }
+    }
 
-    schema.relationships.iter().for_each(|x| {
-        let reference_split: Vec<&str> = x.reference.split("-").collect();
-        let from = reference_split.first().to_owned().unwrap();
-        let to = &reference_split.last().unwrap().to_owned();
-        let from_id = entity_ids.get(*from).unwrap();
-        let to_id = entity_ids.get(*to).unwrap();
-        let key = format!("{}.{}-{}", x.reference, from_id, to_id);
-        let props: serde_json::Value = relationships
-            .get(&x.reference)
-            .unwrap()
+    // adding a key is safe, the load rolls back if existing rows violate it
+    let mut sorted = key.to_vec();
+    sorted.sort();
+    let primary_key = primary_key.map(|(name, columns)| {
+        let mut columns: Vec<String> = columns
             .iter()
-            .map(|prop| {
-                json!({
-                    "label": prop.0,
-                    "value": prop.1,
-                    "dataType": prop.2,
-                })
-            })
+            .map(|x| renamed.get(x.as_str()).map_or(x.clone(), |x| x.to_string()))
             .collect();
+        columns.sort();
+        (name, columns)
+    });
+    match primary_key {
+        Some((_, x)) if x == sorted => {}
+        Some((name, _)) => {
+            changes.push(Change {
+                sql: format!("alter table {} drop constraint {};", fqn_name, quote(&name)),
+                safe: false,
+            });
+            if !key.is_empty() {
+                changes.push(Change {
+                    sql: format!(
+                        "alter table {} add primary key ({});",
+                        fqn_name,
+                        quote_all(key)
+                    ),
+                    safe: false,
+                });
+            }
+        }
+        None if !key.is_empty() => changes.push(Change {
+            sql: format!(
+                "alter table {} add primary key ({});",
+                fqn_name,
+                quote_all(key)
+            ),
+            safe: true,
+        }),
+        None => {}
+    }
 
-        messages.push(data::Message {
-            key,
-            value: json!({
-                "relType": x.label,
-                "fromId": from_id,
-                "toId": to_id,
-                "props": props,
-            })
-            .to_string(),
-        });
-    })
+    Ok(changes)
 }
 
-fn schema_to_ddl(fqn_name: &String, schema: datatypes::SchemaRef) -> Vec<String> {
+fn schema_to_ddl(
+    table: &TableName,
+    schema: datatypes::SchemaRef,
+    options: &data::LoadOptions,
+    alters: &[String],
+) -> Result<Vec<String>, String> {
+    let fqn_name = table.qualified();
     let mut create = String::new();
     writeln!(
         create,
-        "create schema if not exists \"{}\";",
-        fqn_name.split(".").nth(1).unwrap()
+        "create schema if not exists {};",
+        quote(&table.schema)
     )
     .unwrap();
 
-    let mut drop = String::new();
-    writeln!(drop, "drop table if exists {};", fqn_name).unwrap();
-
     let mut ddl = String::new();
-    writeln!(ddl, "create table {} (", fqn_name).unwrap();
-    schema
-        .fields()
-        .into_iter()
-        .enumerate()
-        .map(|(i, x)| {
-            let leading = if i == 0 { "" } else { ", " };
-            match x.data_type() {
-                datatypes::DataType::Utf8 => writeln!(ddl, "\t{}{} varchar", leading, x.name()),
-                datatypes::DataType::Boolean => writeln!(ddl, "\t{}{} bool", leading, x.name()),
-                datatypes::DataType::Int64 => writeln!(ddl, "\t{}{} int", leading, x.name()),
-                datatypes::DataType::Float64 => writeln!(ddl, "\t{}{} float", leading, x.name()),
-                datatypes::DataType::Date64 => writeln!(ddl, "\t{}{} bigint", leading, x.name()),
-                _ => unimplemented!(),
-            }
-        })
-        .for_each(|_| {});
+    let exists = match options.mode {
+        data::LoadMode::Replace => "",
+        _ => "if not exists ",
+    };
+    writeln!(ddl, "create table {}{} (", exists, fqn_name).unwrap();
+    for (i, x) in schema.fields().into_iter().enumerate() {
+        let leading = if i == 0 { "" } else { ", " };
+        let null = if x.is_nullable() { "" } else { " not null" };
+        writeln!(
+            ddl,
+            "\t{}{} {}{}",
+            leading,
+            quote(x.name()),
+            pgcopy::type_name(x)?,
+            null
+        )
+        .unwrap();
+    }
+    if !options.key.is_empty() {
+        writeln!(ddl, "\t, primary key ({})", quote_all(&options.key)).unwrap();
+    }
     writeln!(ddl, ");").unwrap();
 
-    vec![create, drop, ddl]
+    let mut sql = vec![create];
+    let evolve = |sql: &mut Vec<String>| sql.extend(alters.iter().cloned());
+    match &options.mode {
+        data::LoadMode::Replace => {
+            sql.push(format!("drop table if exists {};", fqn_name));
+            sql.push(ddl);
+        }
+        // on conflict arbitrates upserts against the primary key
+        data::LoadMode::Append | data::LoadMode::Upsert(_) => {
+            sql.push(ddl);
+            evolve(&mut sql);
+        }
+        data::LoadMode::Truncate => {
+            sql.push(ddl);
+            evolve(&mut sql);
+            sql.push(format!("truncate table {};", fqn_name));
+        }
+    }
+
+    // named like postgres names its own, so reruns find them
+    let index = |columns: &[String], suffix: &str| {
+        quote(&format!("{}_{}_{}", table.table, columns.join("_"), suffix))
+    };
+    options.unique.iter().for_each(|x| {
+        sql.push(format!(
+            "create unique index if not exists {} on {} ({});",
+            index(x, "key"),
+            fqn_name,
+            quote_all(x)
+        ))
+    });
+    options.indexes.iter().for_each(|x| {
+        sql.push(format!(
+            "create index if not exists {} on {} ({});",
+            index(x, "idx"),
+            fqn_name,
+            quote_all(x)
+        ))
+    });
+
+    Ok(sql)
 }
 
-pub fn from_args(matches: &ArgMatches) -> data::Repository {
-    let uri = matches
-        .get_one::<String>("SERVER")
-        .expect("required")
-        .clone();
-    let user = matches.get_one::<String>("USER").expect("required").clone();
-    let password = matches
-        .get_one::<String>("PASSWORD")
-        .expect("required")
-        .clone();
-    let fqn_table = matches
-        .get_one::<String>("FQN_TABLE")
-        .expect("required")
-        .clone();
-
-    let db = fqn_table.split_once(".").map(|(x, _)| x).unwrap();
-
-    let client = Client::connect(
-        format!(
-            "host={} user={} password={} dbname={}",
-            uri, user, password, db
-        )
-        .as_str(),
-        NoTls,
-    )
-    .expect("failed to connect to postgres");
+pub fn from_args(server: &str, fqn_table: &str) -> data::Repository {
+    let table = TableName::parse(fqn_table).unwrap_or_else(|e| panic!("invalid FQN_TABLE: {}", e));
+
+    let client = pgconn::connect(server, &table.database)
+        .unwrap_or_else(|e| panic!("failed to connect to postgres: {}", e));
 
     data::Repository {
-        database: Box::new(Provider { fqn_table, client }),
+        database: Box::new(Provider {
+            table,
+            client,
+            mode: data::LoadMode::Replace,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(database: &str, schema: &str, table: &str) -> TableName {
+        TableName {
+            database: database.to_string(),
+            schema: schema.to_string(),
+            table: table.to_string(),
+        }
+    }
+
+    #[test]
+    fn table_name_folds_unquoted_parts() {
+        assert_eq!(
+            TableName::parse("testdb.public.Events").unwrap(),
+            table("testdb", "public", "events")
+        );
+        assert_eq!(
+            TableName::parse("TestDb.Sales.\"Events\"").unwrap(),
+            table("TestDb", "sales", "Events")
+        );
+    }
+
+    #[test]
+    fn table_name_keeps_quoted_dots_and_doubled_quotes() {
+        assert_eq!(
+            TableName::parse("testdb.\"my.schema\".\"say \"\"hi\"\"\"").unwrap(),
+            table("testdb", "my.schema", "say \"hi\"")
+        );
+        assert_eq!(
+            TableName::parse("testdb.public.\"a\"\";drop table x;--\"").unwrap(),
+            table("testdb", "public", "a\";drop table x;--")
+        );
+    }
+
+    #[test]
+    fn table_name_rejects_unterminated_quotes() {
+        assert_eq!(
+            TableName::parse("testdb.public.\"events").err().unwrap(),
+            "unterminated quote in testdb.public.\"events"
+        );
+    }
+
+    #[test]
+    fn table_name_needs_three_parts() {
+        for fqn in ["events", "public.events", "a.b.c.d", "testdb..events", ""] {
+            assert_eq!(
+                TableName::parse(fqn).err().unwrap(),
+                format!("expected database.schema.table, got {}", fqn)
+            );
+        }
+    }
+
+    #[test]
+    fn qualified_quotes_every_part() {
+        let name = TableName::parse("testdb.\"my.schema\".\"a\"\"b\"").unwrap();
+        assert_eq!(name.qualified(), "\"my.schema\".\"a\"\"b\"");
+    }
+
+    fn column(name: &str, udt: &str, numeric: Option<(i32, i32)>) -> Column {
+        Column {
+            name: name.to_string(),
+            udt: udt.to_string(),
+            numeric,
+        }
+    }
+
+    fn plan(
+        fields: Vec<datatypes::Field>,
+        columns: &[Column],
+        primary_key: Option<(&str, &[&str])>,
+        key: &[&str],
+    ) -> Vec<(String, bool)> {
+        let primary_key = primary_key
+            .map(|(name, xs)| (name.to_string(), xs.iter().map(|x| x.to_string()).collect()));
+        let key: Vec<String> = key.iter().map(|x| x.to_string()).collect();
+        plan_changes(
+            &table("testdb", "public", "events"),
+            &datatypes::Schema::new(fields),
+            columns,
+            primary_key,
+            &key,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|x| (x.sql, x.safe))
+        .collect()
+    }
+
+    fn field(name: &str, data_type: datatypes::DataType) -> datatypes::Field {
+        datatypes::Field::new(name, data_type, true)
+    }
+
+    fn change(sql: &str, safe: bool) -> (String, bool) {
+        (format!("alter table \"public\".\"events\" {};", sql), safe)
+    }
+
+    #[test]
+    fn plan_nothing_for_a_new_or_matching_table() {
+        let fields = || vec![field("id", datatypes::DataType::Int64)];
+        assert_eq!(plan(fields(), &[], None, &[]), []);
+        assert_eq!(plan(fields(), &[column("id", "int8", None)], None, &[]), []);
+    }
+
+    #[test]
+    fn plan_widening_is_safe() {
+        let fields = vec![
+            field("a", datatypes::DataType::Int64),
+            field("b", datatypes::DataType::Float64),
+            field("c", datatypes::DataType::Decimal128(38, 10)),
+        ];
+        let columns = [
+            column("a", "int4", None),
+            column("b", "float4", None),
+            column("c", "int8", None),
+        ];
+        assert_eq!(
+            plan(fields, &columns, None, &[]),
+            [
+                change("alter column \"a\" type int8 using \"a\"::int8", true),
+                change("alter column \"b\" type float8 using \"b\"::float8", true),
+                change(
+                    "alter column \"c\" type numeric(38, 10) using \"c\"::numeric(38, 10)",
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_narrowing_is_unsafe() {
+        let fields = vec![
+            field("a", datatypes::DataType::Int32),
+            field("b", datatypes::DataType::Utf8),
+            field("c", datatypes::DataType::Decimal128(20, 10)),
+        ];
+        let columns = [
+            column("a", "int8", None),
+            column("b", "int8", None),
+            column("c", "int8", None),
+        ];
+        assert_eq!(
+            plan(fields, &columns, None, &[]),
+            [
+                change("alter column \"a\" type int4 using \"a\"::int4", false),
+                change(
+                    "alter column \"b\" type varchar using \"b\"::varchar",
+                    false
+                ),
+                change(
+                    "alter column \"c\" type numeric(20, 10) using \"c\"::numeric(20, 10)",
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_numeric_precision_and_scale() {
+        let amount = |precision, scale| {
+            vec![field(
+                "amount",
+                datatypes::DataType::Decimal128(precision, scale),
+            )]
+        };
+        let existing = [column("amount", "numeric", Some((10, 2)))];
+        let alter = |p: i32, s: i32, safe| {
+            change(
+                &format!(
+                    "alter column \"amount\" type numeric({p}, {s}) using \"amount\"::numeric({p}, {s})"
+                ),
+                safe,
+            )
+        };
+        assert_eq!(plan(amount(10, 2), &existing, None, &[]), []);
+        assert_eq!(
+            plan(amount(38, 10), &existing, None, &[]),
+            [alter(38, 10, true)]
+        );
+        assert_eq!(
+            plan(amount(12, 4), &existing, None, &[]),
+            [alter(12, 4, true)]
+        );
+        // fewer fraction digits round, fewer integer digits overflow
+        assert_eq!(
+            plan(amount(10, 1), &existing, None, &[]),
+            [alter(10, 1, false)]
+        );
+        assert_eq!(
+            plan(amount(10, 4), &existing, None, &[]),
+            [alter(10, 4, false)]
+        );
+        // an unconstrained numeric already holds anything
+        let unconstrained = [column("amount", "numeric", None)];
+        assert_eq!(plan(amount(10, 2), &unconstrained, None, &[]), []);
+    }
+
+    #[test]
+    fn numeric_of_fields() {
+        assert_eq!(
+            numeric_of(&field("a", datatypes::DataType::Decimal128(12, 3))),
+            Some((12, 3))
+        );
+        assert_eq!(
+            numeric_of(&field("a", datatypes::DataType::Decimal128(12, -2))),
+            Some((12, 0))
+        );
+        assert_eq!(
+            numeric_of(&field("a", datatypes::DataType::UInt64)),
+            Some((20, 0))
+        );
+        assert_eq!(numeric_of(&field("a", datatypes::DataType::Int64)), None);
+    }
+
+    #[test]
+    fn plan_added_and_dropped_columns() {
+        let fields = vec![
+            field("id", datatypes::DataType::Int64),
+            field("note", datatypes::DataType::Utf8),
+            datatypes::Field::new("age", datatypes::DataType::Int64, false),
+        ];
+        let columns = [column("id", "int8", None), column("old", "varchar", None)];
+        assert_eq!(
+            plan(fields, &columns, None, &[]),
+            [
+                change("add column \"note\" varchar", true),
+                change("add column \"age\" int8 not null", false),
+                change("drop column \"old\"", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_renames_folded_legacy_columns() {
+        let fields = vec![
+            field("sourceId", datatypes::DataType::Utf8),
+            field("residencyStartDate", datatypes::DataType::Date64),
+        ];
+        let columns = [
+            column("sourceid", "varchar", None),
+            column("residencystartdate", "int8", None),
+        ];
+        assert_eq!(
+            plan(fields, &columns, Some(("events_pkey", &["sourceid"])), &["sourceId"]),
+            [
+                change("rename column \"sourceid\" to \"sourceId\"", true),
+                change("rename column \"residencystartdate\" to \"residencyStartDate\"", true),
+                change(
+                    "alter column \"residencyStartDate\" type date using \
+                     (timestamp '1970-01-01' + \"residencyStartDate\" * interval '1 millisecond')::date",
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_refuses_ambiguous_folded_columns() {
+        let columns = [
+            column("Name", "varchar", None),
+            column("NAME", "varchar", None),
+        ];
+        let error = plan_changes(
+            &table("testdb", "public", "events"),
+            &datatypes::Schema::new(vec![field("name", datatypes::DataType::Utf8)]),
+            &columns,
+            None,
+            &[],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error,
+            "name matches more than one column of \"public\".\"events\": Name, NAME"
+        );
+    }
+
+    #[test]
+    fn plan_primary_key_changes() {
+        let fields = || {
+            vec![
+                field("a", datatypes::DataType::Int64),
+                field("b", datatypes::DataType::Int64),
+            ]
+        };
+        let columns = [column("a", "int8", None), column("b", "int8", None)];
+        let pkey = Some(("events_pkey", &["a", "b"][..]));
+        assert_eq!(plan(fields(), &columns, pkey, &["b", "a"]), []);
+        assert_eq!(
+            plan(fields(), &columns, None, &["a"]),
+            [change("add primary key (\"a\")", true)]
+        );
+        assert_eq!(
+            plan(fields(), &columns, pkey, &["a"]),
+            [
+                change("drop constraint \"events_pkey\"", false),
+                change("add primary key (\"a\")", false),
+            ]
+        );
+        assert_eq!(
+            plan(fields(), &columns, pkey, &[]),
+            [change("drop constraint \"events_pkey\"", false)]
+        );
     }
 }